@@ -0,0 +1,257 @@
+use ::chrono::{DateTime, Utc};
+use ::itertools::Itertools;
+use ::serde::Deserialize;
+
+use crate::dvb::data::{Platform, TagInfo};
+
+/// One page of `hub.docker.com/v2/namespaces/<namespace>/repositories/<repository>/tags`.
+#[derive(Debug, Deserialize)]
+pub struct DockerHubTagPage {
+    pub next: Option<String>,
+    pub results: Vec<DockerHubTag>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DockerHubTag {
+    pub name: String,
+    pub last_updated: Option<DateTime<Utc>>,
+    pub digest: Option<String>,
+    #[serde(default)]
+    pub images: Vec<DockerHubImage>,
+}
+
+/// One platform of a Docker Hub tag.
+#[derive(Debug, Deserialize)]
+pub struct DockerHubImage {
+    pub architecture: Option<String>,
+    pub os: Option<String>,
+    pub variant: Option<String>,
+}
+
+impl From<DockerHubTag> for TagInfo {
+    fn from(tag: DockerHubTag) -> Self {
+        let platforms = tag
+            .images
+            .into_iter()
+            .filter_map(|image| match (image.os, image.architecture) {
+                (Some(os), Some(architecture)) => {
+                    Some(Platform::new(os, architecture, image.variant))
+                }
+                _ => None,
+            })
+            .unique()
+            .collect();
+        TagInfo::new(tag.name).with_metadata(tag.last_updated, tag.digest, platforms)
+    }
+}
+
+/// Response of the OCI Distribution `/v2/<name>/tags/list` endpoint.
+#[derive(Debug, Deserialize)]
+pub struct OciTagList {
+    /// Some registries return `null` instead of an empty list.
+    pub tags: Option<Vec<String>>,
+}
+
+impl OciTagList {
+    pub fn into_tags(self) -> Vec<TagInfo> {
+        self.tags
+            .unwrap_or_default()
+            .into_iter()
+            .map(TagInfo::new)
+            .collect()
+    }
+}
+
+/// A manifest from the OCI Distribution `/v2/<name>/manifests/<reference>` endpoint, which is either
+/// an index (or Docker manifest list) with a manifest per platform, or a single-platform image manifest.
+#[derive(Debug, Deserialize)]
+pub struct OciManifest {
+    #[serde(default)]
+    pub manifests: Vec<OciManifestDescriptor>,
+    pub config: Option<OciConfigDescriptor>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct OciManifestDescriptor {
+    pub platform: Option<OciPlatform>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct OciConfigDescriptor {
+    pub digest: String,
+}
+
+/// Platform of a manifest in an index, or from the config blob of an image manifest.
+#[derive(Debug, Deserialize)]
+pub struct OciPlatform {
+    pub os: String,
+    pub architecture: String,
+    pub variant: Option<String>,
+}
+
+impl From<OciPlatform> for Platform {
+    fn from(platform: OciPlatform) -> Self {
+        Platform::new(platform.os, platform.architecture, platform.variant)
+    }
+}
+
+impl OciManifest {
+    /// Platforms of an index, without entries like attestations that have platform `unknown/unknown`.
+    pub fn index_platforms(self) -> Vec<Platform> {
+        self.manifests
+            .into_iter()
+            .filter_map(|manifest| manifest.platform)
+            .map(Platform::from)
+            .filter(|platform| platform.os() != "unknown")
+            .unique()
+            .collect()
+    }
+}
+
+/// Errors as returned by Docker Hub (`message` or `detail`) and OCI registries (`errors`).
+#[derive(Debug, Deserialize)]
+pub struct RegistryError {
+    message: Option<String>,
+    detail: Option<String>,
+    #[serde(default)]
+    errors: Vec<OciError>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OciError {
+    code: Option<String>,
+    message: Option<String>,
+}
+
+/// Describe a failed registry response, using the error from the body if there is one.
+pub fn describe_error(status: u16, body: &str) -> String {
+    let description = serde_json::from_str::<RegistryError>(body)
+        .ok()
+        .and_then(|error| {
+            if let Some(message) = error.message.or(error.detail) {
+                return Some(message);
+            }
+            let messages = error
+                .errors
+                .into_iter()
+                .map(|error| match (error.code, error.message) {
+                    (Some(code), Some(message)) => format!("{}: {}", code, message),
+                    (code, message) => code.or(message).unwrap_or_default(),
+                })
+                .filter(|message| !message.is_empty())
+                .join("; ");
+            Some(messages).filter(|messages| !messages.is_empty())
+        });
+    match description {
+        Some(description) => format!("registry error (status {}): {}", status, description),
+        None => format!("registry error (status {})", status),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn docker_hub_page() {
+        let page: DockerHubTagPage = serde_json::from_str(
+            r#"{"count": 2, "next": "https://hub.docker.com/v2/x?page=2", "previous": null, "results": [
+                {"name": "3.13-slim", "last_updated": "2024-10-08T01:23:45.123456Z", "digest": "sha256:aa",
+                 "images": [
+                    {"architecture": "amd64", "os": "linux", "variant": null, "digest": "sha256:bb"},
+                    {"architecture": "arm64", "os": "linux", "variant": "v8", "digest": "sha256:cc"},
+                    {"architecture": "unknown", "os": "unknown"}]},
+                {"name": "3.12-slim", "images": []}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            page.next.as_deref(),
+            Some("https://hub.docker.com/v2/x?page=2")
+        );
+        let tags = page
+            .results
+            .into_iter()
+            .map(TagInfo::from)
+            .collect::<Vec<_>>();
+        assert_eq!(tags[0].name(), "3.13-slim");
+        assert_eq!(tags[0].digest().as_deref(), Some("sha256:aa"));
+        assert_eq!(
+            tags[0].last_updated().unwrap().to_rfc3339(),
+            "2024-10-08T01:23:45.123456+00:00"
+        );
+        assert_eq!(
+            tags[0]
+                .platforms()
+                .iter()
+                .map(|platform| platform.to_string())
+                .collect::<Vec<_>>(),
+            vec!["linux/amd64", "linux/arm64/v8", "unknown/unknown"]
+        );
+        assert_eq!(tags[1], TagInfo::new("3.12-slim".to_owned()));
+    }
+
+    #[test]
+    fn oci_tag_list() {
+        let list: OciTagList =
+            serde_json::from_str(r#"{"name": "user/image", "tags": ["1.0", "latest"]}"#).unwrap();
+        assert_eq!(
+            list.into_tags(),
+            vec![
+                TagInfo::new("1.0".to_owned()),
+                TagInfo::new("latest".to_owned())
+            ]
+        );
+        let list: OciTagList =
+            serde_json::from_str(r#"{"name": "user/image", "tags": null}"#).unwrap();
+        assert_eq!(list.into_tags(), vec![]);
+    }
+
+    #[test]
+    fn manifest_index_platforms() {
+        let index: OciManifest = serde_json::from_str(
+            r#"{"schemaVersion": 2, "mediaType": "application/vnd.oci.image.index.v1+json", "manifests": [
+                {"digest": "sha256:aa", "platform": {"architecture": "amd64", "os": "linux"}},
+                {"digest": "sha256:bb", "platform": {"architecture": "arm64", "os": "linux", "variant": "v8"}},
+                {"digest": "sha256:cc", "platform": {"architecture": "unknown", "os": "unknown"}}
+            ]}"#,
+        )
+        .unwrap();
+        assert!(index.config.is_none());
+        assert_eq!(
+            index
+                .index_platforms()
+                .iter()
+                .map(|platform| platform.to_string())
+                .collect::<Vec<_>>(),
+            vec!["linux/amd64", "linux/arm64/v8"]
+        );
+        let manifest: OciManifest = serde_json::from_str(
+            r#"{"schemaVersion": 2, "config": {"digest": "sha256:dd", "size": 10}, "layers": []}"#,
+        )
+        .unwrap();
+        assert_eq!(manifest.config.unwrap().digest, "sha256:dd");
+    }
+
+    #[test]
+    fn error_messages() {
+        assert_eq!(
+            describe_error(
+                429,
+                r#"{"message": "You have reached your pull rate limit."}"#
+            ),
+            "registry error (status 429): You have reached your pull rate limit."
+        );
+        assert_eq!(
+            describe_error(
+                404,
+                r#"{"errors": [{"code": "NAME_UNKNOWN", "message": "repository name not known to registry"}]}"#
+            ),
+            "registry error (status 404): NAME_UNKNOWN: repository name not known to registry"
+        );
+        assert_eq!(
+            describe_error(502, "<html>Bad gateway</html>"),
+            "registry error (status 502)"
+        );
+    }
+}
@@ -0,0 +1,344 @@
+use ::std::collections::HashMap;
+use ::std::env;
+use ::std::fs;
+use ::std::path::PathBuf;
+use ::std::process::Stdio;
+
+use ::base64::engine::general_purpose::STANDARD as BASE64;
+use ::base64::Engine;
+use ::derive_getters::Getters;
+use ::derive_new::new;
+use ::lazy_static::lazy_static;
+use ::log::{debug, warn};
+use ::regex::Regex;
+use ::reqwest::Url;
+use ::serde::Deserialize;
+use ::tokio::io::AsyncWriteExt;
+use ::tokio::process::Command;
+
+lazy_static! {
+    static ref CHALLENGE_PARAM_RE: Regex = Regex::new("(\\w+)=\"([^\"]*)\"").unwrap();
+}
+
+/// Hosts that all use the Docker Hub credentials.
+static DOCKER_HUB_AUTH_HOSTS: [&str; 5] = [
+    "docker.io",
+    "index.docker.io",
+    "registry-1.docker.io",
+    "hub.docker.com",
+    "auth.docker.io",
+];
+
+static DOCKER_HUB_SERVER_KEY: &str = "docker.io";
+
+/// The server url under which Docker stores Docker Hub credentials.
+static DOCKER_HUB_SERVER_URL: &str = "https://index.docker.io/v1/";
+
+/// Username and password (or access token) for a registry.
+#[derive(Debug, Clone, PartialEq, Eq, new)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+/// Parsed `WWW-Authenticate: Bearer realm="...",service="...",scope="..."` header of a 401 response.
+#[derive(Debug, Clone, PartialEq, Eq, Getters)]
+pub struct BearerChallenge {
+    realm: String,
+    service: Option<String>,
+    scope: Option<String>,
+}
+
+impl BearerChallenge {
+    pub fn parse(header: &str) -> Option<BearerChallenge> {
+        let (scheme, params) = header.trim().split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("bearer") {
+            return None;
+        }
+        let params = CHALLENGE_PARAM_RE
+            .captures_iter(params)
+            .map(|param| (param[1].to_ascii_lowercase(), param[2].to_owned()))
+            .collect::<HashMap<_, _>>();
+        Some(BearerChallenge {
+            realm: params.get("realm")?.to_owned(),
+            service: params.get("service").cloned(),
+            scope: params.get("scope").cloned(),
+        })
+    }
+
+    /// The url to request a token from, for the service and scope in the challenge.
+    pub fn token_url(&self) -> Result<Url, String> {
+        let mut url = Url::parse(&self.realm)
+            .map_err(|err| format!("invalid token realm '{}': {}", &self.realm, err))?;
+        {
+            let mut query = url.query_pairs_mut();
+            if let Some(service) = &self.service {
+                query.append_pair("service", service);
+            }
+            if let Some(scope) = &self.scope {
+                query.append_pair("scope", scope);
+            }
+        }
+        Ok(url)
+    }
+}
+
+/// Token endpoints use either `token` or `access_token`.
+#[derive(Debug, Deserialize)]
+pub struct TokenResponse {
+    token: Option<String>,
+    access_token: Option<String>,
+}
+
+impl TokenResponse {
+    pub fn into_token(self) -> Option<String> {
+        self.token.or(self.access_token)
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct DockerConfig {
+    #[serde(default)]
+    auths: HashMap<String, DockerConfigAuth>,
+    /// Credential helper for all registries, like `desktop` for `docker-credential-desktop`.
+    #[serde(rename = "credsStore")]
+    creds_store: Option<String>,
+    /// Credential helpers per registry, which take precedence over `credsStore`.
+    #[serde(default, rename = "credHelpers")]
+    cred_helpers: HashMap<String, String>,
+}
+
+/// Output of `docker-credential-<helper> get`.
+#[derive(Debug, Deserialize)]
+struct HelperCredentials {
+    #[serde(rename = "Username")]
+    username: String,
+    #[serde(rename = "Secret")]
+    secret: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct DockerConfigAuth {
+    auth: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+}
+
+/// Find credentials for a registry host, from `DVB_REGISTRY_USERNAME` and `DVB_REGISTRY_PASSWORD`
+/// (limited to `DVB_REGISTRY_HOST` if set), or otherwise from the docker `config.json`,
+/// either through a credential helper or from the `auths`.
+pub async fn registry_credentials(host: &str) -> Option<Credentials> {
+    if let Some(credentials) = env_credentials(host) {
+        debug!("using registry credentials from environment for {}", host);
+        return Some(credentials);
+    }
+    let path = docker_config_path()?;
+    let config = fs::read_to_string(&path).ok()?;
+    let config: DockerConfig = match serde_json::from_str(&config) {
+        Ok(config) => config,
+        Err(err) => {
+            warn!(
+                "could not parse docker config {}: {}",
+                path.to_string_lossy(),
+                err
+            );
+            return None;
+        }
+    };
+    if let Some(helper) = credential_helper(&config, host) {
+        return helper_credentials(helper, host).await;
+    }
+    let credentials = docker_config_credentials(&config, host);
+    if credentials.is_some() {
+        debug!(
+            "using registry credentials from {} for {}",
+            path.to_string_lossy(),
+            host
+        );
+    }
+    credentials
+}
+
+fn env_credentials(host: &str) -> Option<Credentials> {
+    let username = env::var("DVB_REGISTRY_USERNAME").ok()?;
+    let password = env::var("DVB_REGISTRY_PASSWORD").ok()?;
+    if let Ok(only_host) = env::var("DVB_REGISTRY_HOST") {
+        if auth_server_key(&only_host) != auth_server_key(host) {
+            return None;
+        }
+    }
+    Some(Credentials::new(username, password))
+}
+
+fn docker_config_path() -> Option<PathBuf> {
+    if let Ok(dir) = env::var("DOCKER_CONFIG") {
+        return Some(PathBuf::from(dir).join("config.json"));
+    }
+    env::var("HOME")
+        .ok()
+        .map(|home| PathBuf::from(home).join(".docker").join("config.json"))
+}
+
+/// The helper from `credHelpers` for this registry, or otherwise `credsStore`.
+fn credential_helper<'a>(config: &'a DockerConfig, host: &str) -> Option<&'a str> {
+    let key = auth_server_key(host);
+    config
+        .cred_helpers
+        .iter()
+        .find(|(server, _)| auth_server_key(server) == key)
+        .map(|(_, helper)| helper)
+        .or(config.creds_store.as_ref())
+        .map(|helper| helper.as_str())
+        .filter(|helper| !helper.is_empty())
+}
+
+/// Run `docker-credential-<helper> get` with the server url on stdin, which prints json credentials.
+async fn helper_credentials(helper: &str, host: &str) -> Option<Credentials> {
+    let program = format!("docker-credential-{}", helper);
+    let server_url = if auth_server_key(host) == DOCKER_HUB_SERVER_KEY {
+        DOCKER_HUB_SERVER_URL.to_owned()
+    } else {
+        auth_server_key(host)
+    };
+    debug!("getting credentials for {} from {}", &server_url, &program);
+    let mut child = match Command::new(&program)
+        .arg("get")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(err) => {
+            warn!("could not run credential helper {}: {}", &program, err);
+            return None;
+        }
+    };
+    if let Some(mut stdin) = child.stdin.take() {
+        if let Err(err) = stdin.write_all(server_url.as_bytes()).await {
+            warn!("could not write to credential helper {}: {}", &program, err);
+            return None;
+        }
+    }
+    let output = match child.wait_with_output().await {
+        Ok(output) => output,
+        Err(err) => {
+            warn!("credential helper {} failed: {}", &program, err);
+            return None;
+        }
+    };
+    if !output.status.success() {
+        debug!(
+            "credential helper {} has no credentials for {}",
+            &program, &server_url
+        );
+        return None;
+    }
+    parse_helper_credentials(&output.stdout)
+}
+
+fn parse_helper_credentials(stdout: &[u8]) -> Option<Credentials> {
+    match serde_json::from_slice::<HelperCredentials>(stdout) {
+        Ok(credentials) => Some(Credentials::new(credentials.username, credentials.secret)),
+        Err(err) => {
+            warn!("could not parse credential helper output: {}", err);
+            None
+        }
+    }
+}
+
+fn docker_config_credentials(config: &DockerConfig, host: &str) -> Option<Credentials> {
+    let key = auth_server_key(host);
+    let auth = config
+        .auths
+        .iter()
+        .find(|(server, _)| auth_server_key(server) == key)
+        .map(|(_, auth)| auth)?;
+    if let (Some(username), Some(password)) = (&auth.username, &auth.password) {
+        return Some(Credentials::new(username.clone(), password.clone()));
+    }
+    let decoded = BASE64.decode(auth.auth.as_ref()?).ok()?;
+    let (username, password) = String::from_utf8(decoded)
+        .ok()?
+        .split_once(':')
+        .map(|(username, password)| (username.to_owned(), password.to_owned()))?;
+    Some(Credentials::new(username, password))
+}
+
+/// Normalize servers like `https://index.docker.io/v1/` or `ghcr.io` to the host they are for.
+pub fn auth_server_key(server: &str) -> String {
+    let host = server
+        .trim_start_matches("https://")
+        .trim_start_matches("http://")
+        .split('/')
+        .next()
+        .unwrap_or(server);
+    if DOCKER_HUB_AUTH_HOSTS.contains(&host) {
+        DOCKER_HUB_SERVER_KEY.to_owned()
+    } else {
+        host.to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_bearer_challenge() {
+        let challenge = BearerChallenge::parse(
+            "Bearer realm=\"https://auth.docker.io/token\",service=\"registry.docker.io\",scope=\"repository:library/python:pull\"",
+        )
+        .unwrap();
+        assert_eq!(challenge.realm(), "https://auth.docker.io/token");
+        assert_eq!(
+            challenge.token_url().unwrap().as_str(),
+            "https://auth.docker.io/token?service=registry.docker.io&scope=repository%3Alibrary%2Fpython%3Apull"
+        );
+        assert_eq!(BearerChallenge::parse("Basic realm=\"x\""), None);
+    }
+
+    #[test]
+    fn docker_config_auths() {
+        let config: DockerConfig = serde_json::from_str(
+            r#"{"auths": {
+            "https://index.docker.io/v1/": {"auth": "dXNlcjpwYXNzOndvcmQ="},
+            "ghcr.io": {"username": "me", "password": "secret"}
+        }}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            docker_config_credentials(&config, "registry-1.docker.io"),
+            Some(Credentials::new("user".to_owned(), "pass:word".to_owned()))
+        );
+        assert_eq!(
+            docker_config_credentials(&config, "ghcr.io"),
+            Some(Credentials::new("me".to_owned(), "secret".to_owned()))
+        );
+        assert_eq!(docker_config_credentials(&config, "quay.io"), None);
+        assert_eq!(credential_helper(&config, "ghcr.io"), None);
+    }
+
+    #[test]
+    fn docker_config_helpers() {
+        let config: DockerConfig = serde_json::from_str(
+            r#"{
+            "auths": {"ghcr.io": {}},
+            "credsStore": "desktop",
+            "credHelpers": {"123.dkr.ecr.eu-west-1.amazonaws.com": "ecr-login"}
+        }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            credential_helper(&config, "123.dkr.ecr.eu-west-1.amazonaws.com"),
+            Some("ecr-login")
+        );
+        assert_eq!(credential_helper(&config, "ghcr.io"), Some("desktop"));
+        assert_eq!(
+            parse_helper_credentials(
+                br#"{"ServerURL": "ghcr.io", "Username": "me", "Secret": "token"}"#
+            ),
+            Some(Credentials::new("me".to_owned(), "token".to_owned()))
+        );
+    }
+}
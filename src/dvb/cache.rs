@@ -0,0 +1,212 @@
+use ::std::path::{Path, PathBuf};
+use ::std::time::Duration;
+
+use ::chrono::{DateTime, Utc};
+use ::log::{debug, warn};
+use ::serde::{Deserialize, Serialize};
+use ::sha2::{Digest, Sha256};
+use ::tokio::fs;
+
+use crate::dvb::data::TagInfo;
+
+/// Tag listings stored on disk, one file per registry repository, and raw tag listing responses
+/// with their validators (`ETag`, `Last-Modified`), one file per url.
+#[derive(Debug)]
+pub struct TagCache {
+    dir: PathBuf,
+    responses_dir: PathBuf,
+}
+
+/// A tag listing page as returned by the registry, for conditional requests.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CachedResponse {
+    pub url: String,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    pub next_url: Option<String>,
+    pub body: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CachedTags {
+    pub fetched: DateTime<Utc>,
+    pub tags: Vec<TagInfo>,
+}
+
+impl CachedTags {
+    pub fn is_fresh(&self, ttl: Duration, now: DateTime<Utc>) -> bool {
+        (now - self.fetched)
+            .to_std()
+            .map(|age| age < ttl)
+            .unwrap_or(true)
+    }
+}
+
+impl TagCache {
+    pub fn new(dir: &Path) -> Self {
+        TagCache {
+            dir: dir.join("tags"),
+            responses_dir: dir.join("responses"),
+        }
+    }
+
+    fn path(&self, repository_key: &str) -> PathBuf {
+        self.dir
+            .join(format!("{}.json", cache_file_name(repository_key)))
+    }
+
+    /// Read cached tags, treating unreadable entries as missing.
+    pub async fn load(&self, repository_key: &str) -> Option<CachedTags> {
+        let path = self.path(repository_key);
+        let data = fs::read_to_string(&path).await.ok()?;
+        match serde_json::from_str(&data) {
+            Ok(cached) => {
+                debug!("read cached tags from {}", path.to_string_lossy());
+                Some(cached)
+            }
+            Err(err) => {
+                warn!(
+                    "ignoring invalid tag cache entry {}: {}",
+                    path.to_string_lossy(),
+                    err
+                );
+                None
+            }
+        }
+    }
+
+    pub async fn store(&self, repository_key: &str, tags: &[TagInfo]) -> Result<(), String> {
+        let path = self.path(repository_key);
+        let cached = CachedTags {
+            fetched: Utc::now(),
+            tags: tags.to_vec(),
+        };
+        let data = serde_json::to_string(&cached)
+            .map_err(|err| format!("could not serialize tag cache entry: {}", err))?;
+        fs::create_dir_all(&self.dir).await.map_err(|err| {
+            format!(
+                "could not create tag cache directory {}: {}",
+                self.dir.to_string_lossy(),
+                err
+            )
+        })?;
+        fs::write(&path, data).await.map_err(|err| {
+            format!(
+                "could not write tag cache entry {}: {}",
+                path.to_string_lossy(),
+                err
+            )
+        })
+    }
+
+    /// Urls can be too long for a file name, so use their hash, which stays the same between runs.
+    fn response_path(&self, url: &str) -> PathBuf {
+        let hash = Sha256::digest(url.as_bytes());
+        let name = hash
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<String>();
+        self.responses_dir.join(format!("{}.json", name))
+    }
+
+    pub async fn load_response(&self, url: &str) -> Option<CachedResponse> {
+        let path = self.response_path(url);
+        let data = fs::read_to_string(&path).await.ok()?;
+        serde_json::from_str::<CachedResponse>(&data)
+            .ok()
+            .filter(|response| response.url == url)
+    }
+
+    pub async fn store_response(&self, response: &CachedResponse) -> Result<(), String> {
+        let path = self.response_path(&response.url);
+        let data = serde_json::to_string(response)
+            .map_err(|err| format!("could not serialize cached response: {}", err))?;
+        fs::create_dir_all(&self.responses_dir)
+            .await
+            .map_err(|err| {
+                format!(
+                    "could not create response cache directory {}: {}",
+                    self.responses_dir.to_string_lossy(),
+                    err
+                )
+            })?;
+        fs::write(&path, data).await.map_err(|err| {
+            format!(
+                "could not write cached response {}: {}",
+                path.to_string_lossy(),
+                err
+            )
+        })
+    }
+}
+
+/// Escape the characters in a repository key that cannot be used in file names.
+fn cache_file_name(repository_key: &str) -> String {
+    repository_key
+        .chars()
+        .map(|chr| match chr {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '-' | '_' => chr.to_string(),
+            _ => format!("%{:02X}", chr as u32),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use ::tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn escape_file_name() {
+        assert_eq!(
+            cache_file_name("localhost:5000/user/image"),
+            "localhost%3A5000%2Fuser%2Fimage"
+        );
+    }
+
+    #[tokio::test]
+    async fn store_and_load() {
+        let dir = TempDir::new().unwrap();
+        let cache = TagCache::new(dir.path());
+        assert!(cache.load("docker.io/library/python").await.is_none());
+        let tags = vec![
+            TagInfo::new("3.11".to_owned()),
+            TagInfo::new("3.12".to_owned()),
+        ];
+        cache
+            .store("docker.io/library/python", &tags)
+            .await
+            .unwrap();
+        let cached = cache.load("docker.io/library/python").await.unwrap();
+        assert_eq!(cached.tags, tags);
+        assert!(cached.is_fresh(Duration::from_secs(60), Utc::now()));
+        assert!(!cached.is_fresh(Duration::ZERO, Utc::now()));
+    }
+
+    #[tokio::test]
+    async fn store_and_load_response() {
+        let dir = TempDir::new().unwrap();
+        let cache = TagCache::new(dir.path());
+        let url = "https://ghcr.io/v2/user/image/tags/list";
+        assert!(cache.load_response(url).await.is_none());
+        let response = CachedResponse {
+            url: url.to_owned(),
+            etag: Some("\"abc\"".to_owned()),
+            last_modified: None,
+            next_url: Some("/v2/user/image/tags/list?last=b".to_owned()),
+            body: "{\"tags\": [\"a\", \"b\"]}".to_owned(),
+        };
+        cache.store_response(&response).await.unwrap();
+        assert!(dir
+            .path()
+            .join("responses")
+            .join("229856e858b2c04deb268b2bdfd75bd3953c2efd9f7b6612345881fc7f8f002c.json")
+            .is_file());
+        assert_eq!(cache.load_response(url).await, Some(response));
+        assert!(cache
+            .load_response("https://ghcr.io/v2/other")
+            .await
+            .is_none());
+    }
+}
@@ -0,0 +1,499 @@
+use ::std::collections::HashMap;
+use ::std::sync::Mutex;
+use ::std::time::Duration;
+
+use ::chrono::{DateTime, Utc};
+use ::log::{debug, info, warn};
+use ::reqwest::header::{HeaderMap, CONTENT_TYPE, RETRY_AFTER, WWW_AUTHENTICATE};
+use ::reqwest::{Client, Method, RequestBuilder, Response, StatusCode, Url};
+use ::tokio::time::sleep;
+
+use crate::dvb::auth::{
+    auth_server_key, registry_credentials, BearerChallenge, Credentials, TokenResponse,
+};
+use crate::dvb::registry::RegistryOptions;
+
+static DOCKER_HUB_API_HOST: &str = "hub.docker.com";
+
+/// Below this many remaining requests, the rate limit is logged as a warning.
+static LOW_RATE_LIMIT: u64 = 10;
+
+/// Http client that authenticates with registries, reusing tokens for the whole run,
+/// and that retries failed requests.
+#[derive(Debug)]
+pub struct RegistryClient {
+    client: Client,
+    max_retries: u32,
+    retry_delay: Duration,
+    max_retry_delay: Duration,
+    /// Tokens per registry host and repository.
+    tokens: Mutex<HashMap<(String, String), String>>,
+    /// Credentials per registry, looked up once.
+    credentials: Mutex<HashMap<String, Option<Credentials>>>,
+    /// When the registry rate limit was used up, the time at which it resets.
+    rate_limit_reset: Mutex<Option<DateTime<Utc>>>,
+}
+
+/// Why a request failed. Only transport errors (like connection problems and timeouts) are retried.
+enum SendError {
+    Transport(String),
+    Other(String),
+}
+
+impl SendError {
+    fn into_message(self) -> String {
+        match self {
+            SendError::Transport(msg) | SendError::Other(msg) => msg,
+        }
+    }
+}
+
+impl RegistryClient {
+    pub fn new(options: &RegistryOptions) -> Self {
+        RegistryClient {
+            client: Client::new(),
+            max_retries: options.max_retries,
+            retry_delay: options.retry_delay,
+            max_retry_delay: options.max_retry_delay,
+            tokens: Mutex::new(HashMap::new()),
+            credentials: Mutex::new(HashMap::new()),
+            rate_limit_reset: Mutex::new(None),
+        }
+    }
+
+    pub async fn get(
+        &self,
+        url: &str,
+        repository: &str,
+        headers: &HeaderMap,
+    ) -> Result<Response, String> {
+        self.send(Method::GET, url, repository, headers).await
+    }
+
+    pub async fn head(
+        &self,
+        url: &str,
+        repository: &str,
+        headers: &HeaderMap,
+    ) -> Result<Response, String> {
+        self.send(Method::HEAD, url, repository, headers).await
+    }
+
+    /// Send a request, retrying with exponential backoff on network errors, rate limits and server errors.
+    /// Other errors, like a missing image or failed authentication, are returned right away.
+    async fn send(
+        &self,
+        method: Method,
+        url: &str,
+        repository: &str,
+        headers: &HeaderMap,
+    ) -> Result<Response, String> {
+        let mut attempt = 0;
+        loop {
+            self.wait_for_rate_limit(url).await?;
+            let result = self
+                .send_authenticated(method.clone(), url, repository, headers)
+                .await;
+            let (reason, requested_delay) = match &result {
+                Ok(resp) => {
+                    let rate_limit = RateLimit::parse(resp.headers(), Utc::now());
+                    if let Some(rate_limit) = &rate_limit {
+                        rate_limit.log(url);
+                        if rate_limit.is_used_up() {
+                            *self.rate_limit_reset.lock().unwrap() = rate_limit.reset;
+                        }
+                    }
+                    if !is_retryable(resp.status()) {
+                        return result.map_err(SendError::into_message);
+                    }
+                    let reset_delay = rate_limit
+                        .filter(RateLimit::is_used_up)
+                        .and_then(|rate_limit| rate_limit.reset)
+                        .map(|reset| (reset - Utc::now()).to_std().unwrap_or(Duration::ZERO));
+                    (
+                        format!("status {}", resp.status()),
+                        retry_after(resp.headers(), Utc::now()).or(reset_delay),
+                    )
+                }
+                Err(SendError::Transport(err)) => (err.clone(), None),
+                Err(SendError::Other(_)) => return result.map_err(SendError::into_message),
+            };
+            if attempt >= self.max_retries {
+                return result.map_err(SendError::into_message);
+            }
+            if let Some(requested_delay) = requested_delay {
+                if requested_delay > self.max_retry_delay {
+                    warn!(
+                        "registry asks to wait {} s before retrying {}, not retrying",
+                        requested_delay.as_secs(),
+                        url
+                    );
+                    return result.map_err(SendError::into_message);
+                }
+            }
+            let delay = requested_delay.unwrap_or_else(|| backoff_delay(self.retry_delay, attempt));
+            warn!(
+                "request to {} failed ({}), retry {} of {} in {} ms",
+                url,
+                reason,
+                attempt + 1,
+                self.max_retries,
+                delay.as_millis()
+            );
+            sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    /// If an earlier response used up the rate limit, wait until it resets, unless that takes too long.
+    async fn wait_for_rate_limit(&self, url: &str) -> Result<(), String> {
+        let Some(reset) = *self.rate_limit_reset.lock().unwrap() else {
+            return Ok(());
+        };
+        let Ok(delay) = (reset - Utc::now()).to_std() else {
+            return Ok(());
+        };
+        if delay > self.max_retry_delay {
+            return Err(format!(
+                "registry rate limit is used up until {}, not requesting {}",
+                reset.to_rfc3339(),
+                url
+            ));
+        }
+        info!(
+            "registry rate limit is used up, waiting {} s before requesting {}",
+            delay.as_secs(),
+            url
+        );
+        sleep(delay).await;
+        Ok(())
+    }
+
+    /// Send a request, and if the registry answers with a bearer challenge, get a token and retry.
+    async fn send_authenticated(
+        &self,
+        method: Method,
+        url: &str,
+        repository: &str,
+        headers: &HeaderMap,
+    ) -> Result<Response, SendError> {
+        let host = url_host(url).map_err(SendError::Other)?;
+        let key = (host.clone(), repository.to_owned());
+        let mut token = self.tokens.lock().unwrap().get(&key).cloned();
+        if token.is_none() && host == DOCKER_HUB_API_HOST {
+            // The Docker Hub api does not send challenges, it needs a login for private repositories.
+            if let Some(credentials) = self.credentials_for(&host).await {
+                let login_token = self
+                    .docker_hub_login(&credentials)
+                    .await
+                    .map_err(SendError::Other)?;
+                self.tokens
+                    .lock()
+                    .unwrap()
+                    .insert(key.clone(), login_token.clone());
+                token = Some(login_token);
+            }
+        }
+        let resp = self
+            .request(method.clone(), url, headers, token.as_deref())
+            .send()
+            .await
+            .map_err(|err| {
+                SendError::Transport(format!("Failed to request {}: err {}", url, err))
+            })?;
+        if resp.status() != StatusCode::UNAUTHORIZED {
+            return Ok(resp);
+        }
+        let Some(challenge) = resp
+            .headers()
+            .get(WWW_AUTHENTICATE)
+            .and_then(|header| header.to_str().ok())
+            .and_then(BearerChallenge::parse)
+        else {
+            return Ok(resp);
+        };
+        let new_token = self
+            .request_token(&host, &challenge)
+            .await
+            .map_err(SendError::Other)?;
+        self.tokens.lock().unwrap().insert(key, new_token.clone());
+        self.request(method, url, headers, Some(&new_token))
+            .send()
+            .await
+            .map_err(|err| SendError::Transport(format!("Failed to request {}: err {}", url, err)))
+    }
+
+    fn request(
+        &self,
+        method: Method,
+        url: &str,
+        headers: &HeaderMap,
+        token: Option<&str>,
+    ) -> RequestBuilder {
+        debug!("request to: {}", url);
+        let mut request = self.client.request(method, url).headers(headers.clone());
+        if let Some(token) = token {
+            request = request.bearer_auth(token);
+        }
+        request
+    }
+
+    async fn request_token(
+        &self,
+        host: &str,
+        challenge: &BearerChallenge,
+    ) -> Result<String, String> {
+        let url = challenge.token_url()?;
+        debug!("requesting registry token from: {}", &url);
+        let mut request = self.client.get(url.clone());
+        if let Some(credentials) = self.credentials_for(host).await {
+            request = request.basic_auth(&credentials.username, Some(&credentials.password));
+        }
+        let resp = request
+            .send()
+            .await
+            .map_err(|err| format!("Failed to request registry token: err {} for {}", err, &url))?;
+        if !resp.status().is_success() {
+            return Err(format!(
+                "Failed to request registry token: status {} for {}",
+                resp.status(),
+                &url
+            ));
+        }
+        let data = resp
+            .text()
+            .await
+            .map_err(|err| format!("Failed to request registry token: err {} for {}", err, &url))?;
+        serde_json::from_str::<TokenResponse>(&data)
+            .ok()
+            .and_then(TokenResponse::into_token)
+            .ok_or_else(|| format!("No token in registry token response from {}", &url))
+    }
+
+    async fn docker_hub_login(&self, credentials: &Credentials) -> Result<String, String> {
+        let url = format!("https://{}/v2/users/login", DOCKER_HUB_API_HOST);
+        debug!("logging in to Docker Hub as {}", &credentials.username);
+        let body = serde_json::json!({
+            "username": &credentials.username,
+            "password": &credentials.password,
+        });
+        let resp = self
+            .client
+            .post(&url)
+            .header(CONTENT_TYPE, "application/json")
+            .body(body.to_string())
+            .send()
+            .await
+            .map_err(|err| format!("Failed to log in to Docker Hub: err {}", err))?;
+        if !resp.status().is_success() {
+            return Err(format!(
+                "Failed to log in to Docker Hub as {}: status {}",
+                &credentials.username,
+                resp.status()
+            ));
+        }
+        let data = resp
+            .text()
+            .await
+            .map_err(|err| format!("Failed to log in to Docker Hub: err {}", err))?;
+        serde_json::from_str::<TokenResponse>(&data)
+            .ok()
+            .and_then(TokenResponse::into_token)
+            .ok_or_else(|| "No token in Docker Hub login response".to_owned())
+    }
+
+    async fn credentials_for(&self, host: &str) -> Option<Credentials> {
+        let key = auth_server_key(host);
+        if let Some(credentials) = self.credentials.lock().unwrap().get(&key) {
+            return credentials.clone();
+        }
+        let credentials = registry_credentials(host).await;
+        self.credentials
+            .lock()
+            .unwrap()
+            .insert(key, credentials.clone());
+        credentials
+    }
+}
+
+fn url_host(url: &str) -> Result<String, String> {
+    let url = Url::parse(url).map_err(|err| format!("invalid url '{}': {}", url, err))?;
+    let host = url
+        .host_str()
+        .ok_or_else(|| format!("no host in url '{}'", url))?;
+    Ok(match url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_owned(),
+    })
+}
+
+fn is_retryable(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+/// Exponential backoff with up to 50% random jitter, so that concurrent requests do not retry in sync.
+fn backoff_delay(base: Duration, attempt: u32) -> Duration {
+    let delay = base.saturating_mul(2u32.saturating_pow(attempt));
+    let jitter_ms = fastrand::u64(0..=(delay.as_millis() as u64 / 2));
+    delay + Duration::from_millis(jitter_ms)
+}
+
+/// The `Retry-After` header, which is either a number of seconds or a http date.
+fn retry_after(headers: &HeaderMap, now: DateTime<Utc>) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    Some(
+        (date.with_timezone(&Utc) - now)
+            .to_std()
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+/// The rate limit headers that Docker Hub sends, either as `X-RateLimit-*` or as `RateLimit-*`.
+#[derive(Debug, PartialEq, Eq)]
+struct RateLimit {
+    /// Like `76;w=21600`.
+    remaining: String,
+    limit: String,
+    reset: Option<DateTime<Utc>>,
+}
+
+impl RateLimit {
+    fn parse(headers: &HeaderMap, now: DateTime<Utc>) -> Option<Self> {
+        let remaining = rate_limit_header(headers, "remaining")?.to_owned();
+        let limit = rate_limit_header(headers, "limit")
+            .unwrap_or("?")
+            .to_owned();
+        // The reset is a unix timestamp (`X-RateLimit-Reset`) or a number of seconds (`RateLimit-Reset`).
+        let reset = rate_limit_header(headers, "reset")
+            .and_then(|reset| reset.trim().parse::<i64>().ok())
+            .and_then(|reset| match reset {
+                reset if reset > 1_000_000_000 => DateTime::from_timestamp(reset, 0),
+                seconds => Some(now + chrono::Duration::seconds(seconds)),
+            });
+        Some(RateLimit {
+            remaining,
+            limit,
+            reset,
+        })
+    }
+
+    fn remaining_count(&self) -> Option<u64> {
+        self.remaining
+            .split(';')
+            .next()
+            .and_then(|count| count.trim().parse::<u64>().ok())
+    }
+
+    fn is_used_up(&self) -> bool {
+        self.remaining_count() == Some(0)
+    }
+
+    fn log(&self, url: &str) {
+        match self.remaining_count() {
+            Some(count) if count < LOW_RATE_LIMIT => warn!(
+                "registry rate limit almost used up: {} of {} requests remaining (from {})",
+                self.remaining, self.limit, url
+            ),
+            _ => info!(
+                "registry rate limit: {} of {} requests remaining (from {})",
+                self.remaining, self.limit, url
+            ),
+        }
+    }
+}
+
+fn rate_limit_header<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get(format!("x-ratelimit-{}", name))
+        .or_else(|| headers.get(format!("ratelimit-{}", name)))
+        .and_then(|value| value.to_str().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use ::reqwest::header::HeaderValue;
+
+    use super::*;
+
+    #[test]
+    fn backoff_grows() {
+        let base = Duration::from_millis(100);
+        for attempt in 0..4 {
+            let delay = backoff_delay(base, attempt);
+            let min = base * 2u32.pow(attempt);
+            assert!(delay >= min && delay <= min * 3 / 2, "{:?}", delay);
+        }
+    }
+
+    #[test]
+    fn parse_retry_after() {
+        let now = DateTime::parse_from_rfc3339("2015-10-21T07:27:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let mut headers = HeaderMap::new();
+        assert_eq!(retry_after(&headers, now), None);
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("120"));
+        assert_eq!(retry_after(&headers, now), Some(Duration::from_secs(120)));
+        headers.insert(
+            RETRY_AFTER,
+            HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"),
+        );
+        assert_eq!(retry_after(&headers, now), Some(Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn retryable_status() {
+        assert!(is_retryable(StatusCode::TOO_MANY_REQUESTS));
+        assert!(is_retryable(StatusCode::BAD_GATEWAY));
+        assert!(is_retryable(StatusCode::HTTP_VERSION_NOT_SUPPORTED));
+        assert!(!is_retryable(StatusCode::UNAUTHORIZED));
+        assert!(!is_retryable(StatusCode::FORBIDDEN));
+        assert!(!is_retryable(StatusCode::NOT_FOUND));
+        assert!(!is_retryable(StatusCode::OK));
+    }
+
+    #[tokio::test]
+    async fn do_not_retry_invalid_url() {
+        let client = RegistryClient::new(&RegistryOptions {
+            retry_delay: Duration::from_secs(60),
+            ..RegistryOptions::default()
+        });
+        let result = tokio::time::timeout(
+            Duration::from_secs(5),
+            client.get("not a url", "library/python", &HeaderMap::new()),
+        )
+        .await
+        .unwrap();
+        assert!(result.unwrap_err().starts_with("invalid url 'not a url'"));
+    }
+
+    #[test]
+    fn parse_rate_limit() {
+        let now = DateTime::parse_from_rfc3339("2015-10-21T07:27:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let mut headers = HeaderMap::new();
+        assert_eq!(RateLimit::parse(&headers, now), None);
+        headers.insert("x-ratelimit-limit", HeaderValue::from_static("100"));
+        headers.insert("x-ratelimit-remaining", HeaderValue::from_static("0"));
+        headers.insert("x-ratelimit-reset", HeaderValue::from_static("1445412480"));
+        let rate_limit = RateLimit::parse(&headers, now).unwrap();
+        assert!(rate_limit.is_used_up());
+        assert_eq!(rate_limit.reset, Some(now + chrono::Duration::seconds(60)));
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "ratelimit-remaining",
+            HeaderValue::from_static("76;w=21600"),
+        );
+        headers.insert("ratelimit-reset", HeaderValue::from_static("30"));
+        let rate_limit = RateLimit::parse(&headers, now).unwrap();
+        assert_eq!(rate_limit.remaining_count(), Some(76));
+        assert_eq!(rate_limit.limit, "?");
+        assert!(!rate_limit.is_used_up());
+        assert_eq!(rate_limit.reset, Some(now + chrono::Duration::seconds(30)));
+    }
+}
@@ -0,0 +1,90 @@
+use ::std::collections::HashMap;
+use ::std::path::Path;
+
+use ::serde::Deserialize;
+use ::tokio::fs;
+
+use crate::dvb::policy::BumpPolicy;
+
+/// Name of the project config file that is used if it exists in the working directory.
+pub static DEFAULT_CONFIG_FILE: &str = ".dvb.json";
+
+/// Project settings from a json file, like
+/// `{"images": {"postgres": {"constraint": "<17"}, "python": {"level": "patch"}}}`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProjectConfig {
+    #[serde(default)]
+    images: HashMap<String, ImageConfig>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ImageConfig {
+    level: Option<String>,
+    constraint: Option<String>,
+}
+
+impl ProjectConfig {
+    pub async fn load(path: &Path) -> Result<Self, String> {
+        let data = fs::read_to_string(path).await.map_err(|err| {
+            format!(
+                "could not read config file {}: {}",
+                path.to_string_lossy(),
+                err
+            )
+        })?;
+        serde_json::from_str(&data)
+            .map_err(|err| format!("invalid config file {}: {}", path.to_string_lossy(), err))
+    }
+
+    /// Add the per-image settings to the policy.
+    pub fn apply_to(&self, policy: &mut BumpPolicy) -> Result<(), String> {
+        for (image, config) in &self.images {
+            if let Some(level) = &config.level {
+                policy.image_levels.insert(image.clone(), level.parse()?);
+            }
+            if let Some(constraint) = &config.constraint {
+                policy
+                    .constraints
+                    .insert(image.clone(), constraint.parse()?);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dvb::policy::BumpLevel;
+
+    use super::*;
+
+    #[test]
+    fn apply_image_settings() {
+        let config: ProjectConfig = serde_json::from_str(
+            r#"{"images": {
+                "postgres": {"constraint": ">=15, <17"},
+                "python": {"level": "patch", "constraint": "<3.13"}
+            }}"#,
+        )
+        .unwrap();
+        let mut policy = BumpPolicy::default();
+        config.apply_to(&mut policy).unwrap();
+        assert_eq!(policy.image_levels.get("python"), Some(&BumpLevel::Patch));
+        assert_eq!(policy.image_levels.get("postgres"), None);
+        assert_eq!(
+            policy.constraints.get("postgres").unwrap().to_string(),
+            ">=15, <17"
+        );
+        assert_eq!(policy.constraints.len(), 2);
+    }
+
+    #[test]
+    fn reject_invalid() {
+        assert!(serde_json::from_str::<ProjectConfig>(r#"{"image": {}}"#).is_err());
+        let config: ProjectConfig =
+            serde_json::from_str(r#"{"images": {"python": {"level": "huge"}}}"#).unwrap();
+        assert!(config.apply_to(&mut BumpPolicy::default()).is_err());
+    }
+}
@@ -0,0 +1,173 @@
+use ::std::cmp::Ordering;
+use ::std::fmt;
+use ::std::str::FromStr;
+
+use crate::dvb::data::Tag;
+
+/// Version requirement like `>=3.11, <3.13`, which must hold for every comma-separated comparison.
+/// Versions are compared up to the number of components in the constraint, so `3.13.2` is not `<3.13`,
+/// and `=3.11` allows any `3.11.x`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VersionConstraint {
+    comparisons: Vec<Comparison>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Comparison {
+    operator: Operator,
+    nrs: Vec<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operator {
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+    Equal,
+    NotEqual,
+}
+
+impl Operator {
+    fn symbol(&self) -> &'static str {
+        match self {
+            Operator::Less => "<",
+            Operator::LessOrEqual => "<=",
+            Operator::Greater => ">",
+            Operator::GreaterOrEqual => ">=",
+            Operator::Equal => "=",
+            Operator::NotEqual => "!=",
+        }
+    }
+}
+
+impl VersionConstraint {
+    /// Whether the version numbers of the tag satisfy all comparisons.
+    pub fn matches(&self, tag: &Tag) -> bool {
+        let (nr1, nr2, nr3, nr4) = *tag.nrs();
+        let nrs = [nr1, nr2, nr3, nr4];
+        self.comparisons.iter().all(|comparison| {
+            let ordering = nrs[..comparison.nrs.len()].cmp(&comparison.nrs);
+            match comparison.operator {
+                Operator::Less => ordering == Ordering::Less,
+                Operator::LessOrEqual => ordering != Ordering::Greater,
+                Operator::Greater => ordering == Ordering::Greater,
+                Operator::GreaterOrEqual => ordering != Ordering::Less,
+                Operator::Equal => ordering == Ordering::Equal,
+                Operator::NotEqual => ordering != Ordering::Equal,
+            }
+        })
+    }
+}
+
+impl FromStr for VersionConstraint {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let comparisons = text
+            .split(',')
+            .map(|comparison| parse_comparison(comparison.trim()))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| format!("invalid version constraint '{}': {}", text, err))?;
+        Ok(VersionConstraint { comparisons })
+    }
+}
+
+fn parse_comparison(text: &str) -> Result<Comparison, String> {
+    // Longer operators first, so that `<=` is not read as `<` followed by `=3.11`.
+    let operators = [
+        ("<=", Operator::LessOrEqual),
+        (">=", Operator::GreaterOrEqual),
+        ("!=", Operator::NotEqual),
+        ("==", Operator::Equal),
+        ("<", Operator::Less),
+        (">", Operator::Greater),
+        ("=", Operator::Equal),
+    ];
+    let (operator, version) = operators
+        .iter()
+        .find_map(|(symbol, operator)| {
+            text.strip_prefix(symbol)
+                .map(|version| (*operator, version.trim()))
+        })
+        .ok_or_else(|| format!("'{}' should start with <, <=, >, >=, = or !=", text))?;
+    let nrs = version
+        .split('.')
+        .map(|nr| nr.parse::<u32>())
+        .collect::<Result<Vec<_>, _>>()
+        .ok()
+        .filter(|nrs| (1..=4).contains(&nrs.len()))
+        .ok_or_else(|| {
+            format!(
+                "'{}' should be a version with 1 to 4 numbers like 3.11",
+                version
+            )
+        })?;
+    Ok(Comparison { operator, nrs })
+}
+
+impl fmt::Display for VersionConstraint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, comparison) in self.comparisons.iter().enumerate() {
+            if index > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", comparison.operator.symbol())?;
+            for (index, nr) in comparison.nrs.iter().enumerate() {
+                if index > 0 {
+                    write!(f, ".")?;
+                }
+                write!(f, "{}", nr)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(constraint: &str, nrs: (u32, u32, u32, u32)) -> bool {
+        constraint
+            .parse::<VersionConstraint>()
+            .unwrap()
+            .matches(&Tag::new("".to_owned(), nrs))
+    }
+
+    #[test]
+    fn range() {
+        assert!(!matches(">=3.11, <3.13", (3, 10, 9, 0)));
+        assert!(matches(">=3.11, <3.13", (3, 11, 0, 0)));
+        assert!(matches(">=3.11, <3.13", (3, 12, 7, 0)));
+        assert!(!matches(">=3.11, <3.13", (3, 13, 0, 0)));
+        assert!(!matches(">=3.11, <3.13", (3, 13, 2, 0)));
+    }
+
+    #[test]
+    fn precision() {
+        assert!(matches("<17", (16, 4, 0, 0)));
+        assert!(!matches("<17", (17, 0, 0, 0)));
+        assert!(matches("=3.11", (3, 11, 9, 0)));
+        assert!(matches("==3.11", (3, 11, 0, 0)));
+        assert!(!matches("!=3.12", (3, 12, 1, 0)));
+        assert!(matches("<=3.12", (3, 12, 9, 0)));
+        assert!(matches(">3.12", (3, 13, 0, 0)));
+        assert!(!matches(">3.12", (3, 12, 5, 0)));
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!("3.11".parse::<VersionConstraint>().is_err());
+        assert!("<3.x".parse::<VersionConstraint>().is_err());
+        assert!(">=3.11,".parse::<VersionConstraint>().is_err());
+        assert!("<1.2.3.4.5".parse::<VersionConstraint>().is_err());
+        assert_eq!(
+            ">= 3.11 ,<3.13"
+                .parse::<VersionConstraint>()
+                .unwrap()
+                .to_string(),
+            ">=3.11, <3.13"
+        );
+    }
+}
@@ -2,13 +2,24 @@ use ::lazy_static::lazy_static;
 use ::regex::Match;
 use ::regex::Regex;
 
-use crate::dvb::data::Tag;
+use crate::dvb::data::{PreRelease, PreReleaseKind, Tag};
 
 lazy_static! {
     static ref TAG_DIGITS_RE: Regex = Regex::new(r"[0-9]+").unwrap();
+    /// The first version number with dots in a tag, like `3.11.2` in `3.11.2-slim`.
+    static ref VERSION_NRS_RE: Regex = Regex::new(r"[0-9]+(?:\.[0-9]+)+").unwrap();
+    /// A pre-release like `rc1`, `-beta.2` or `a1` at the start of the text, not followed by a letter.
+    /// Without a number (like `-dev`) it is a flavour of the image, which is kept as part of the format.
+    static ref PRE_RELEASE_RE: Regex =
+        Regex::new(r"^([-.]?(?i:dev|alpha|beta|rc|a|b)\.?[0-9]+)(?:[^a-zA-Z]|$)").unwrap();
 }
 
-pub(crate) fn escape_re(pattern: &str) -> String {
+/// Optionally matches a numbered pre-release after the version numbers, in named groups so that they are
+/// not mistaken for version numbers.
+pub(crate) static PRE_RELEASE_PATTERN: &str =
+    r"(?:[-.]?(?P<pre>(?i:dev|alpha|beta|rc|a|b))\.?(?P<pre_nr>[0-9]+))?";
+
+fn escape_re(pattern: &str) -> String {
     pattern.replace('-', r"\-").replace('.', r"\.")
 }
 
@@ -18,8 +29,25 @@ fn tag_re_str(tag_str: &str) -> String {
     tag_digits_replaced.into_owned()
 }
 
+/// Pattern that matches tags with the same format, with the digits as groups. After the version numbers,
+/// any pre-release (or none) is matched, so that final releases and pre-releases are found for both.
 pub(crate) fn tag_to_re(tag_str: &str) -> Result<Regex, String> {
-    let tag_digits_replaced = tag_re_str(tag_str);
+    let tag_digits_replaced = match VERSION_NRS_RE.find(tag_str) {
+        Some(version) => {
+            let rest = &tag_str[version.end()..];
+            let pre_release_len = PRE_RELEASE_RE
+                .captures(rest)
+                .map(|pre_release| pre_release[1].len())
+                .unwrap_or(0);
+            format!(
+                "{}{}{}",
+                tag_re_str(&tag_str[..version.end()]),
+                PRE_RELEASE_PATTERN,
+                tag_re_str(&rest[pre_release_len..])
+            )
+        }
+        None => tag_re_str(tag_str),
+    };
     let tag_full_match_re = format!("^{}$", tag_digits_replaced);
     let regex = Regex::new(tag_full_match_re.as_ref()).map_err(|err| {
         format!(
@@ -30,23 +58,6 @@ pub(crate) fn tag_to_re(tag_str: &str) -> Result<Regex, String> {
     Ok(regex)
 }
 
-pub(crate) fn image_tag_to_re(image: &str, tag: &str, suffix: &str) -> Result<Regex, String> {
-    let tag_digits_replaced = tag_re_str(tag);
-    let pattern_str = format!(
-        r"(?m)^FROM\s+{}:{}\b(\s*{})?$",
-        escape_re(image),
-        tag_digits_replaced,
-        escape_re(suffix)
-    );
-    let pattern = Regex::new(&pattern_str).map_err(|err| {
-        format!(
-            "image and tag could not be turned into regex pattern; {}, err: {}",
-            pattern_str, err
-        )
-    })?;
-    Ok(pattern)
-}
-
 pub fn parse_tag(tag_pattern: &Regex, tag: impl Into<String>) -> Result<Tag, String> {
     let tag = tag.into();
     let parts = tag_pattern.captures(&tag).ok_or_else(|| {
@@ -56,13 +67,24 @@ pub fn parse_tag(tag_pattern: &Regex, tag: impl Into<String>) -> Result<Tag, Str
             tag_pattern.as_str()
         )
     })?;
-    let nrs = (
-        match_to_nr(parts.get(1)),
-        match_to_nr(parts.get(2)),
-        match_to_nr(parts.get(3)),
-        match_to_nr(parts.get(4)),
-    );
-    Ok(Tag::new(tag, nrs))
+    // Version numbers are in the unnamed groups, skipping the whole match.
+    let mut nr_matches = tag_pattern
+        .capture_names()
+        .enumerate()
+        .skip(1)
+        .filter(|(_, name)| name.is_none())
+        .map(|(index, _)| parts.get(index));
+    let mut next_nr = || match_to_nr(nr_matches.next().flatten());
+    let nrs = (next_nr(), next_nr(), next_nr(), next_nr());
+    let pre_release = parts
+        .name("pre")
+        .and_then(|kind| PreReleaseKind::parse(kind.as_str()))
+        .map(|kind| PreRelease::new(kind, match_to_nr(parts.name("pre_nr"))));
+    let tag = Tag::new(tag, nrs);
+    Ok(match pre_release {
+        Some(pre_release) => tag.with_pre_release(pre_release),
+        None => tag,
+    })
 }
 
 fn match_to_nr(mtch: Option<Match>) -> u32 {
@@ -75,11 +97,49 @@ mod tests {
     use super::*;
 
     #[test]
-    fn image_tag_to_re_test() {
-        let pattern = image_tag_to_re("namespace/image", "1.2.4-alpha", "AS build").unwrap();
+    fn pre_release_pattern() {
+        let pattern = tag_to_re("3.13.0rc1-slim").unwrap();
+        assert_eq!(pattern.as_str(), tag_to_re("3.13.0-slim").unwrap().as_str());
+        assert!(pattern.is_match("3.13.0-slim"));
+        assert!(pattern.is_match("3.14.0b2-slim"));
+        assert!(!pattern.is_match("3.13.0rc1"));
+        assert_eq!(
+            tag_to_re("0.4.4-rc1").unwrap().as_str(),
+            format!(r"^([0-9]+)\.([0-9]+)\.([0-9]+){}$", PRE_RELEASE_PATTERN)
+        );
+        assert_eq!(
+            tag_to_re("3.11-bookworm").unwrap().as_str(),
+            format!(r"^([0-9]+)\.([0-9]+){}\-bookworm$", PRE_RELEASE_PATTERN)
+        );
+        let flavour = tag_to_re("8.2-dev").unwrap();
+        assert_eq!(
+            flavour.as_str(),
+            format!(r"^([0-9]+)\.([0-9]+){}\-dev$", PRE_RELEASE_PATTERN)
+        );
+        assert!(flavour.is_match("8.3-dev"));
+        assert!(!flavour.is_match("8.4"));
+        assert!(!parse_tag(&flavour, "8.3-dev").unwrap().is_pre_release());
+    }
+
+    #[test]
+    fn parse_pre_release() {
+        let pattern = tag_to_re("3.13.0-slim").unwrap();
+        let tag = |name: &str| parse_tag(&pattern, name).unwrap();
+        assert_eq!(tag("3.13.0-slim").nrs(), &(3, 13, 0, 0));
+        assert!(!tag("3.13.0-slim").is_pre_release());
+        assert_eq!(
+            tag("3.13.0rc1-slim").pre_release(),
+            &Some(PreRelease::new(PreReleaseKind::Rc, 1))
+        );
         assert_eq!(
-            pattern.as_str(),
-            r"(?m)^FROM\s+namespace/image:([0-9]+)\.([0-9]+)\.([0-9]+)\-alpha\b(\s*AS build)?$"
+            tag("3.13.0-beta.2-slim").pre_release(),
+            &Some(PreRelease::new(PreReleaseKind::Beta, 2))
         );
+        assert!(tag("3.13.0a1-slim") < tag("3.13.0b1-slim"));
+        assert!(tag("3.13.0b1-slim") < tag("3.13.0rc1-slim"));
+        assert!(tag("3.13.0rc1-slim") < tag("3.13.0rc2-slim"));
+        assert!(tag("3.13.0rc2-slim") < tag("3.13.0-slim"));
+        assert!(tag("3.13.0-slim") < tag("3.13.1a1-slim"));
+        assert!(tag("3.13.0dev1-slim") < tag("3.13.0a1-slim"));
     }
 }
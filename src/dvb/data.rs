@@ -2,17 +2,38 @@ use ::std::cmp::Ordering;
 use ::std::fmt;
 use ::std::hash;
 use ::std::hash::Hasher;
+use ::std::ops::Range;
 use ::std::path::PathBuf;
 use ::std::rc::Rc;
 
+use ::chrono::{DateTime, Utc};
 use ::derive_getters::Getters;
 use ::derive_new::new;
 use ::regex::Regex;
+use ::serde::{Deserialize, Serialize};
 
-#[derive(Debug, Getters, new)]
+use crate::dvb::parse::{find_stages, parse_instructions, Instruction, Stage};
+use crate::dvb::pragma::Pragmas;
+
+#[derive(Debug, Getters)]
 pub struct Dockerfile {
     path: PathBuf,
     content: String,
+    instructions: Vec<Instruction>,
+    stages: Vec<Stage>,
+}
+
+impl Dockerfile {
+    pub fn new(path: PathBuf, content: String) -> Self {
+        let instructions = parse_instructions(&content);
+        let stages = find_stages(&instructions);
+        Dockerfile {
+            path,
+            content,
+            instructions,
+            stages,
+        }
+    }
 }
 
 impl PartialEq for Dockerfile {
@@ -25,7 +46,7 @@ impl Eq for Dockerfile {}
 
 impl PartialOrd for Dockerfile {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        self.path.partial_cmp(&other.path)
+        Some(self.cmp(other))
     }
 }
 
@@ -42,22 +63,79 @@ pub struct Parent {
     tag_pattern: Regex,
     tag: Tag,
     suffix: String,
+    /// Options like `--platform=$BUILDPLATFORM` that appear before the image in the FROM line.
+    #[new(default)]
+    flags: Vec<String>,
+    /// Set if the tag refers to a global `ARG`, in which case the `ARG` default is bumped instead.
+    #[new(default)]
+    tag_arg: Option<TagArg>,
+    /// Pinned manifest digest like `sha256:...` after the tag, which is refreshed when bumping.
+    #[new(default)]
+    digest: Option<String>,
+    /// Byte range in the Dockerfile that is replaced by the new tag, or by the new default for `tag_arg`.
+    #[new(default)]
+    tag_span: Option<Range<usize>>,
+    /// Byte range of the digest (without `@`) in the Dockerfile.
+    #[new(default)]
+    digest_span: Option<Range<usize>>,
+    /// Settings from `# dvb:...` comments above the FROM line.
+    #[new(default)]
+    pragmas: Pragmas,
 }
 
 impl Parent {
-    pub fn explode(self) -> (PathBuf, String, Tag) {
+    pub fn with_flags(mut self, flags: Vec<String>) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    pub fn with_tag_arg(mut self, tag_arg: TagArg) -> Self {
+        self.tag_arg = Some(tag_arg);
+        self
+    }
+
+    pub fn with_digest(mut self, digest: String) -> Self {
+        self.digest = Some(digest);
+        self
+    }
+
+    pub fn with_spans(mut self, tag_span: Range<usize>, digest_span: Option<Range<usize>>) -> Self {
+        self.tag_span = Some(tag_span);
+        self.digest_span = digest_span;
+        self
+    }
+
+    pub fn with_pragmas(mut self, pragmas: Pragmas) -> Self {
+        self.pragmas = pragmas;
+        self
+    }
+
+    /// The platform from a `--platform` flag, unless it depends on an argument like `$BUILDPLATFORM`.
+    pub fn platform(&self) -> Option<Platform> {
+        self.flags
+            .iter()
+            .filter_map(|flag| flag.strip_prefix("--platform="))
+            .find(|platform| !platform.contains('$'))
+            .and_then(|platform| Platform::parse(platform).ok())
+    }
+
+    pub fn explode(self) -> (PathBuf, String, Tag, Option<String>) {
         let Parent {
             dockerfile,
             image_name: name,
             tag,
+            digest,
             ..
         } = self;
-        (dockerfile.path().to_owned(), name, tag)
+        (dockerfile.path().to_owned(), name, tag, digest)
     }
 }
 
 impl fmt::Display for Parent {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for flag in &self.flags {
+            write!(f, "{} ", flag)?;
+        }
         if self.suffix.is_empty() {
             write!(
                 f,
@@ -83,7 +161,12 @@ impl PartialEq for Parent {
     fn eq(&self, other: &Self) -> bool {
         self.dockerfile.path() == other.dockerfile.path()
             && self.image_name == other.image_name
+            && self.tag_pattern.as_str() == other.tag_pattern.as_str()
             && self.tag == other.tag
+            && self.digest == other.digest
+            && self.tag_span == other.tag_span
+            // Lines that share an `ARG` are only the same if they are both ignored or both not.
+            && self.pragmas.ignore() == other.pragmas.ignore()
     }
 }
 
@@ -95,6 +178,31 @@ impl hash::Hash for Parent {
         state.write(self.image_name.as_bytes());
         state.write(self.tag_pattern.as_str().as_bytes());
         self.tag.hash(state);
+        self.digest.hash(state);
+        self.tag_span.hash(state);
+        self.pragmas.ignore().hash(state);
+    }
+}
+
+/// A global `ARG` (declared before the first `FROM`) whose default value is used in an image tag,
+/// like `PYTHON_VERSION` in `FROM python:${PYTHON_VERSION}-slim`.
+#[derive(Debug, Clone, PartialEq, Eq, Getters, new)]
+pub struct TagArg {
+    name: String,
+    value: String,
+    /// Text in the tag before the `ARG` reference.
+    prefix: String,
+    /// Text in the tag after the `ARG` reference, like `-slim`.
+    postfix: String,
+}
+
+impl TagArg {
+    /// The `ARG` default that would make the tag equal to `new_tag`, if the tag fits the template.
+    pub fn value_for(&self, new_tag: &str) -> Option<String> {
+        new_tag
+            .strip_prefix(&self.prefix)
+            .and_then(|rest| rest.strip_suffix(&self.postfix))
+            .map(|value| value.to_owned())
     }
 }
 
@@ -102,11 +210,35 @@ impl hash::Hash for Parent {
 pub struct Tag {
     name: String,
     nrs: (u32, u32, u32, u32),
+    /// Pre-release like `rc1` after the version numbers, `None` for final releases.
+    #[new(default)]
+    pre_release: Option<PreRelease>,
+    /// Manifest digest in the registry, only looked up for parents that pin a digest.
+    #[new(default)]
+    digest: Option<String>,
+    /// Newer tags that were not chosen because of the bump policy.
+    #[new(default)]
+    held_back: Vec<HeldBack>,
 }
 
 impl Tag {
-    pub fn major(&self) -> u32 {
-        self.nrs.0
+    pub fn with_pre_release(mut self, pre_release: PreRelease) -> Self {
+        self.pre_release = Some(pre_release);
+        self
+    }
+
+    pub fn is_pre_release(&self) -> bool {
+        self.pre_release.is_some()
+    }
+
+    pub fn with_digest(mut self, digest: String) -> Self {
+        self.digest = Some(digest);
+        self
+    }
+
+    pub fn with_held_back(mut self, held_back: Vec<HeldBack>) -> Self {
+        self.held_back = held_back;
+        self
     }
 }
 
@@ -118,19 +250,29 @@ impl fmt::Display for Tag {
 
 impl PartialEq for Tag {
     fn eq(&self, other: &Self) -> bool {
-        self.nrs == other.nrs
+        self.nrs == other.nrs && self.pre_release == other.pre_release
     }
 }
 
 impl Eq for Tag {}
 
 impl PartialOrd for Tag {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> { Some(self.cmp(other)) }
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
 }
 
 impl Ord for Tag {
+    /// Pre-releases come before the final release with the same version numbers.
     fn cmp(&self, other: &Self) -> Ordering {
-        self.nrs.cmp(&other.nrs)
+        self.nrs
+            .cmp(&other.nrs)
+            .then_with(|| match (&self.pre_release, &other.pre_release) {
+                (None, None) => Ordering::Equal,
+                (None, Some(_)) => Ordering::Greater,
+                (Some(_), None) => Ordering::Less,
+                (Some(pre1), Some(pre2)) => pre1.cmp(pre2),
+            })
     }
 }
 
@@ -140,5 +282,122 @@ impl hash::Hash for Tag {
         state.write_u32(self.nrs.1);
         state.write_u32(self.nrs.2);
         state.write_u32(self.nrs.3);
+        self.pre_release.hash(state);
+    }
+}
+
+/// Stage of a pre-release, from earliest to latest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum PreReleaseKind {
+    Dev,
+    Alpha,
+    Beta,
+    Rc,
+}
+
+impl PreReleaseKind {
+    /// Parse an identifier like `rc`, `beta` or `a` (case-insensitive).
+    pub fn parse(text: &str) -> Option<PreReleaseKind> {
+        match text.to_ascii_lowercase().as_str() {
+            "dev" => Some(PreReleaseKind::Dev),
+            "a" | "alpha" => Some(PreReleaseKind::Alpha),
+            "b" | "beta" => Some(PreReleaseKind::Beta),
+            "rc" => Some(PreReleaseKind::Rc),
+            _ => None,
+        }
+    }
+}
+
+/// A pre-release like `rc1` or `beta.2`, which is ordered by kind and then by number.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Getters, new)]
+pub struct PreRelease {
+    kind: PreReleaseKind,
+    nr: u32,
+}
+
+/// A tag that is newer than the chosen one, but is not allowed by the bump policy.
+#[derive(Debug, Clone, PartialEq, Eq, Getters, new)]
+pub struct HeldBack {
+    tag: String,
+    reason: String,
+}
+
+impl fmt::Display for HeldBack {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", &self.tag, &self.reason)
+    }
+}
+
+/// A tag as listed by the registry, with any metadata that the registry provides.
+#[derive(Debug, Clone, PartialEq, Eq, Getters, Serialize, Deserialize, new)]
+pub struct TagInfo {
+    name: String,
+    #[new(default)]
+    #[serde(default)]
+    last_updated: Option<DateTime<Utc>>,
+    #[new(default)]
+    #[serde(default)]
+    digest: Option<String>,
+    /// Platforms the tag is built for, empty if unknown.
+    #[new(default)]
+    #[serde(default)]
+    platforms: Vec<Platform>,
+}
+
+impl TagInfo {
+    pub fn with_metadata(
+        mut self,
+        last_updated: Option<DateTime<Utc>>,
+        digest: Option<String>,
+        platforms: Vec<Platform>,
+    ) -> Self {
+        self.last_updated = last_updated;
+        self.digest = digest;
+        self.platforms = platforms;
+        self
+    }
+}
+
+/// Platform of an image, like `linux/arm64/v8`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Getters, Serialize, Deserialize, new)]
+pub struct Platform {
+    os: String,
+    architecture: String,
+    variant: Option<String>,
+}
+
+impl Platform {
+    /// Parse a platform like `linux/amd64` or `linux/arm64/v8`.
+    pub fn parse(text: &str) -> Result<Platform, String> {
+        let parts = text.split('/').collect::<Vec<_>>();
+        if parts.iter().any(|part| part.is_empty()) || !(2..=3).contains(&parts.len()) {
+            return Err(format!(
+                "invalid platform '{}', expected os/architecture[/variant] like linux/amd64",
+                text
+            ));
+        }
+        Ok(Platform::new(
+            parts[0].to_owned(),
+            parts[1].to_owned(),
+            parts.get(2).map(|variant| variant.to_string()),
+        ))
+    }
+
+    /// Whether an image for this platform can be used when `required` is asked for.
+    /// If the required platform has no variant, any variant is accepted.
+    pub fn satisfies(&self, required: &Platform) -> bool {
+        self.os == required.os
+            && self.architecture == required.architecture
+            && (required.variant.is_none() || self.variant == required.variant)
+    }
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", &self.os, &self.architecture)?;
+        if let Some(variant) = &self.variant {
+            write!(f, "/{}", variant)?;
+        }
+        Ok(())
     }
 }
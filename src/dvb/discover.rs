@@ -0,0 +1,278 @@
+use ::std::collections::BTreeSet;
+use ::std::path::Path;
+use ::std::path::PathBuf;
+
+use ::globset::{Glob, GlobBuilder, GlobMatcher, GlobSet, GlobSetBuilder};
+use ::ignore::WalkBuilder;
+use ::lazy_static::lazy_static;
+use ::log::debug;
+
+lazy_static! {
+    /// File names that are recognized as Dockerfiles when searching directories.
+    static ref DOCKERFILE_NAMES: GlobSet = {
+        let mut builder = GlobSetBuilder::new();
+        for pattern in ["Dockerfile", "*.Dockerfile", "Dockerfile.*", "Containerfile"] {
+            builder.add(Glob::new(pattern).unwrap());
+        }
+        builder.build().unwrap()
+    };
+}
+
+/// Name of the files with exclude patterns (in `.gitignore` syntax) that are honoured in each directory.
+pub static IGNORE_FILE_NAME: &str = ".dvbignore";
+
+/// Settings for finding Dockerfiles in directories and glob patterns.
+#[derive(Debug, Clone, Default)]
+pub struct DiscoverOptions {
+    /// Glob patterns of files and directories to skip, matched against the path and the file name, like `legacy`.
+    pub excludes: Vec<String>,
+    /// Extra files with exclude patterns in `.gitignore` syntax, like a `.dockerignore`.
+    pub ignore_files: Vec<PathBuf>,
+}
+
+/// Expand directories and glob patterns into Dockerfile paths, keeping other paths as they are.
+/// Directories are searched recursively for `Dockerfile`, `*.Dockerfile`, `Dockerfile.*` and `Containerfile`,
+/// while glob patterns like `services/**/Dockerfile` match any file name. Both skip files excluded
+/// by `.gitignore`, `.dvbignore`, the ignore files or the exclude patterns.
+pub fn find_dockerfiles(
+    paths: &[PathBuf],
+    options: &DiscoverOptions,
+) -> Result<Vec<PathBuf>, String> {
+    let excludes = build_glob_set(&options.excludes)?;
+    let mut dockerfiles = BTreeSet::new();
+    for path in paths {
+        let text = path.to_string_lossy();
+        let found = if is_glob(&text) {
+            let path = without_dot_prefix(path);
+            let pattern = glob_matcher(path)?;
+            walk(&glob_root(path), options, &excludes)?
+                .into_iter()
+                .filter(|file| pattern.is_match(file))
+                .collect::<Vec<_>>()
+        } else if path.is_dir() {
+            walk(path, options, &excludes)?
+                .into_iter()
+                .filter(|file| is_dockerfile_name(file))
+                .collect::<Vec<_>>()
+        } else {
+            vec![path.clone()]
+        };
+        if found.is_empty() {
+            return Err(format!("no Dockerfiles found for '{}'", text));
+        }
+        debug!("found {} Dockerfile(s) for '{}'", found.len(), text);
+        dockerfiles.extend(found);
+    }
+    Ok(dockerfiles.into_iter().collect())
+}
+
+fn build_glob_set(patterns: &[String]) -> Result<GlobSet, String> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(
+            GlobBuilder::new(pattern)
+                .literal_separator(true)
+                .build()
+                .map_err(|err| format!("invalid exclude pattern '{}': {}", pattern, err))?,
+        );
+    }
+    builder
+        .build()
+        .map_err(|err| format!("invalid exclude patterns: {}", err))
+}
+
+/// Matcher for the pattern, which should not start with `./`, like the paths from `walk`.
+fn glob_matcher(pattern: &Path) -> Result<GlobMatcher, String> {
+    let text = pattern.to_string_lossy();
+    Ok(GlobBuilder::new(&text)
+        .literal_separator(true)
+        .build()
+        .map_err(|err| format!("invalid glob pattern '{}': {}", text, err))?
+        .compile_matcher())
+}
+
+fn is_glob(path: &str) -> bool {
+    path.contains(['*', '?', '[', '{'])
+}
+
+fn is_dockerfile_name(path: &Path) -> bool {
+    // BuildKit reads `Dockerfile.dockerignore` as the ignore file for `Dockerfile`.
+    path.file_name().is_some_and(|name| {
+        DOCKERFILE_NAMES.is_match(name) && !name.to_string_lossy().ends_with(".dockerignore")
+    })
+}
+
+/// The directory to search for a glob pattern, which is the part before the first component with a wildcard.
+fn glob_root(pattern: &Path) -> PathBuf {
+    let root = pattern
+        .components()
+        .take_while(|component| !is_glob(&component.as_os_str().to_string_lossy()))
+        .collect::<PathBuf>();
+    if root.as_os_str().is_empty() {
+        PathBuf::from(".")
+    } else {
+        root
+    }
+}
+
+/// Paths are relative to the working directory without a `./` prefix, so that they match patterns like `api/*`.
+fn without_dot_prefix(path: &Path) -> &Path {
+    path.strip_prefix(".").unwrap_or(path)
+}
+
+/// All files in the directory that are not excluded, including those in hidden directories like `.devcontainer`.
+fn walk(
+    root: &Path,
+    options: &DiscoverOptions,
+    excludes: &GlobSet,
+) -> Result<Vec<PathBuf>, String> {
+    let mut builder = WalkBuilder::new(root);
+    builder
+        .hidden(false)
+        .require_git(false)
+        .add_custom_ignore_filename(IGNORE_FILE_NAME);
+    for ignore_file in &options.ignore_files {
+        if let Some(err) = builder.add_ignore(ignore_file) {
+            return Err(format!(
+                "could not read ignore file '{}': {}",
+                ignore_file.to_string_lossy(),
+                err
+            ));
+        }
+    }
+    let excludes = excludes.clone();
+    builder.filter_entry(move |entry| {
+        entry.file_name() != ".git"
+            && !excludes.is_match(without_dot_prefix(entry.path()))
+            && !excludes.is_match(entry.file_name())
+    });
+    let mut files = vec![];
+    for entry in builder.build() {
+        let entry = entry.map_err(|err| {
+            format!(
+                "could not search '{}' for Dockerfiles: {}",
+                root.to_string_lossy(),
+                err
+            )
+        })?;
+        if entry
+            .file_type()
+            .is_some_and(|file_type| file_type.is_file())
+        {
+            files.push(without_dot_prefix(entry.path()).to_path_buf());
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use ::std::fs;
+
+    use ::tempfile::TempDir;
+
+    use super::*;
+
+    fn project() -> TempDir {
+        let dir = TempDir::new().unwrap();
+        for file in [
+            "Dockerfile",
+            "Dockerfile.dockerignore",
+            "api/api.Dockerfile",
+            "api/Dockerfile.dev",
+            "api/main.rs",
+            "worker/Containerfile",
+            "vendor/Dockerfile",
+            "legacy/Dockerfile",
+            "docs/Dockerfile",
+            ".devcontainer/Dockerfile",
+        ] {
+            let path = dir.path().join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "FROM alpine:3.20\n").unwrap();
+        }
+        fs::write(dir.path().join(".gitignore"), "vendor/\n").unwrap();
+        fs::write(dir.path().join(".dvbignore"), "docs\n").unwrap();
+        dir
+    }
+
+    fn relative(dir: &TempDir, paths: Vec<PathBuf>) -> Vec<String> {
+        paths
+            .into_iter()
+            .map(|path| {
+                path.strip_prefix(dir.path())
+                    .unwrap()
+                    .to_string_lossy()
+                    .into_owned()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn search_directory() {
+        let dir = project();
+        let options = DiscoverOptions {
+            excludes: vec!["legacy".to_owned()],
+            ..DiscoverOptions::default()
+        };
+        let found = find_dockerfiles(&[dir.path().to_path_buf()], &options).unwrap();
+        assert_eq!(
+            relative(&dir, found),
+            vec![
+                ".devcontainer/Dockerfile",
+                "Dockerfile",
+                "api/Dockerfile.dev",
+                "api/api.Dockerfile",
+                "worker/Containerfile",
+            ]
+        );
+    }
+
+    #[test]
+    fn glob_patterns() {
+        let dir = project();
+        let ignore_file = dir.path().join("extra.ignore");
+        fs::write(&ignore_file, "*.dev\n").unwrap();
+        let options = DiscoverOptions {
+            ignore_files: vec![ignore_file],
+            ..DiscoverOptions::default()
+        };
+        let pattern = dir.path().join("api/*");
+        let found = find_dockerfiles(&[pattern], &options).unwrap();
+        assert_eq!(
+            relative(&dir, found),
+            vec!["api/api.Dockerfile", "api/main.rs"]
+        );
+        let pattern = dir.path().join("*/Dockerfile");
+        let found = find_dockerfiles(&[pattern], &options).unwrap();
+        assert_eq!(
+            relative(&dir, found),
+            vec![".devcontainer/Dockerfile", "legacy/Dockerfile"]
+        );
+        let pattern = dir.path().join("**/*.Containerfile");
+        assert!(find_dockerfiles(&[pattern], &options).is_err());
+    }
+
+    #[test]
+    fn keep_plain_paths() {
+        let paths = vec![PathBuf::from("missing/Dockerfile")];
+        assert_eq!(
+            find_dockerfiles(&paths, &DiscoverOptions::default()).unwrap(),
+            paths
+        );
+        assert_eq!(
+            glob_root(Path::new("services/*/Dockerfile")),
+            PathBuf::from("services")
+        );
+        assert_eq!(glob_root(Path::new("**/Dockerfile")), PathBuf::from("."));
+    }
+
+    #[test]
+    fn dot_prefixed_glob() {
+        let pattern = without_dot_prefix(Path::new("./services/**/Dockerfile"));
+        assert_eq!(glob_root(pattern), PathBuf::from("services"));
+        let matcher = glob_matcher(pattern).unwrap();
+        assert!(matcher.is_match("services/api/Dockerfile"));
+        assert!(!matcher.is_match("other/api/Dockerfile"));
+    }
+}
@@ -0,0 +1,281 @@
+use ::std::collections::HashMap;
+use ::std::path::{Path, PathBuf};
+
+use ::async_trait::async_trait;
+use ::itertools::Itertools;
+use ::log::debug;
+use ::serde::Deserialize;
+use ::tokio::fs;
+
+use crate::dvb::api::OciPlatform;
+use crate::dvb::data::{Platform, TagInfo};
+use crate::dvb::registry::ImageRef;
+use crate::dvb::source::{StaticTagSource, TagSource};
+
+/// Annotation with the reference (tag or full image reference) of a manifest in an OCI image layout.
+static REF_NAME_ANNOTATION: &str = "org.opencontainers.image.ref.name";
+
+/// The `index.json` of an OCI image layout.
+#[derive(Debug, Deserialize)]
+struct OciIndex {
+    #[serde(default)]
+    manifests: Vec<OciDescriptor>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OciDescriptor {
+    digest: Option<String>,
+    platform: Option<OciPlatform>,
+    #[serde(default)]
+    annotations: HashMap<String, String>,
+}
+
+/// Tags from an OCI image layout directory, like one made by `skopeo copy` into `oci:<dir>:<ref>`.
+/// The `index.json` in the directory itself may contain full references like `python:3.11`,
+/// and `<dir>/<registry>/<path>` (e.g. `<dir>/docker.io/library/python`) may contain a layout with only tags.
+#[derive(Debug)]
+pub struct OciLayoutTagSource {
+    dir: PathBuf,
+}
+
+impl OciLayoutTagSource {
+    pub fn new(dir: &Path) -> Self {
+        OciLayoutTagSource {
+            dir: dir.to_owned(),
+        }
+    }
+}
+
+#[async_trait]
+impl TagSource for OciLayoutTagSource {
+    async fn tags(&self, image: &ImageRef) -> Result<Vec<TagInfo>, String> {
+        let key = image.repository_key();
+        let shared_index = read_oci_index(&self.dir).await?;
+        let image_index = read_oci_index(&self.dir.join(&key)).await?;
+        let shared_tags = shared_index
+            .into_iter()
+            .flat_map(|index| index.manifests)
+            .filter_map(|descriptor| layout_tag(descriptor, &key, false));
+        let image_tags = image_index
+            .into_iter()
+            .flat_map(|index| index.manifests)
+            .filter_map(|descriptor| layout_tag(descriptor, &key, true));
+        let tags = merge_layout_tags(shared_tags.chain(image_tags));
+        if tags.is_empty() {
+            return Err(format!(
+                "no tags for {} in OCI image layout {}",
+                image,
+                self.dir.to_string_lossy()
+            ));
+        }
+        Ok(tags)
+    }
+}
+
+async fn read_oci_index(dir: &Path) -> Result<Option<OciIndex>, String> {
+    let path = dir.join("index.json");
+    let Ok(data) = fs::read_to_string(&path).await else {
+        debug!("no OCI image layout index at {}", path.to_string_lossy());
+        return Ok(None);
+    };
+    serde_json::from_str(&data)
+        .map(Some)
+        .map_err(|err| format!("invalid OCI index {}: {}", path.to_string_lossy(), err))
+}
+
+/// The tag of a manifest if it belongs to the repository, which is implied for bare tags if `allow_bare`.
+fn layout_tag(
+    descriptor: OciDescriptor,
+    repository_key: &str,
+    allow_bare: bool,
+) -> Option<TagInfo> {
+    let reference = descriptor.annotations.get(REF_NAME_ANNOTATION)?;
+    let image = ImageRef::parse(reference);
+    let name = match image.tag() {
+        Some(tag) if image.repository_key() == repository_key => tag.clone(),
+        Some(_) => return None,
+        None if allow_bare => reference.clone(),
+        None => return None,
+    };
+    let platforms = descriptor
+        .platform
+        .map(Platform::from)
+        .into_iter()
+        .collect();
+    Some(TagInfo::new(name).with_metadata(None, descriptor.digest, platforms))
+}
+
+/// A layout can have one manifest per platform for the same tag, which are combined into one tag.
+fn merge_layout_tags(tags: impl Iterator<Item = TagInfo>) -> Vec<TagInfo> {
+    tags.into_group_map_by(|tag| tag.name().clone())
+        .into_iter()
+        .sorted_by(|(name1, _), (name2, _)| name1.cmp(name2))
+        .map(|(name, tags)| {
+            let digest = tags.iter().map(|tag| tag.digest().clone()).next().flatten();
+            let platforms = tags
+                .into_iter()
+                .flat_map(|tag| tag.platforms().clone())
+                .unique()
+                .collect();
+            TagInfo::new(name).with_metadata(None, digest, platforms)
+        })
+        .collect()
+}
+
+/// Output of `skopeo list-tags`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct SkopeoTags {
+    repository: String,
+    tags: Vec<String>,
+}
+
+/// A tag in a snapshot file, which is either just the name or a tag with metadata.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum SnapshotTag {
+    Name(String),
+    Info(TagInfo),
+}
+
+/// Snapshot files contain the output of one or more `skopeo list-tags`, or map image names to tags.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Snapshot {
+    Skopeo(SkopeoTags),
+    SkopeoList(Vec<SkopeoTags>),
+    Images(HashMap<String, Vec<SnapshotTag>>),
+}
+
+impl StaticTagSource {
+    /// Read tags from a json snapshot file, either a mapping from image names to tags like
+    /// `{"python": ["3.11", "3.12"]}`, or the output of `skopeo list-tags` (or a list of those).
+    pub async fn from_snapshot_file(path: &Path) -> Result<Self, String> {
+        let data = fs::read_to_string(path).await.map_err(|err| {
+            format!(
+                "could not read tag snapshot {}: {}",
+                path.to_string_lossy(),
+                err
+            )
+        })?;
+        Self::from_snapshot(&data)
+            .map_err(|err| format!("invalid tag snapshot {}: {}", path.to_string_lossy(), err))
+    }
+
+    fn from_snapshot(data: &str) -> Result<Self, String> {
+        let snapshot: Snapshot = serde_json::from_str(data).map_err(|err| err.to_string())?;
+        let skopeo_tags = |skopeo: SkopeoTags| {
+            (
+                skopeo.repository,
+                skopeo.tags.into_iter().map(TagInfo::new).collect(),
+            )
+        };
+        let images: Vec<(String, Vec<TagInfo>)> = match snapshot {
+            Snapshot::Skopeo(skopeo) => vec![skopeo_tags(skopeo)],
+            Snapshot::SkopeoList(list) => list.into_iter().map(skopeo_tags).collect(),
+            Snapshot::Images(images) => images
+                .into_iter()
+                .map(|(image, tags)| {
+                    let tags = tags
+                        .into_iter()
+                        .map(|tag| match tag {
+                            SnapshotTag::Name(name) => TagInfo::new(name),
+                            SnapshotTag::Info(info) => info,
+                        })
+                        .collect();
+                    (image, tags)
+                })
+                .collect(),
+        };
+        Ok(images
+            .into_iter()
+            .fold(StaticTagSource::new(), |source, (image, tags)| {
+                source.with_tags(&image, tags)
+            }))
+    }
+}
+
+/// Tag source for files on disk: an OCI image layout if the path is a directory, a json snapshot otherwise.
+pub async fn local_tag_source(path: &Path) -> Result<Box<dyn TagSource>, String> {
+    if path.is_dir() {
+        Ok(Box::new(OciLayoutTagSource::new(path)))
+    } else {
+        Ok(Box::new(StaticTagSource::from_snapshot_file(path).await?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ::tempfile::TempDir;
+
+    use super::*;
+
+    fn manifest(reference: &str, digest: &str, architecture: &str) -> String {
+        format!(
+            "{{\"mediaType\": \"application/vnd.oci.image.manifest.v1+json\", \"digest\": \"{}\", \
+            \"size\": 100, \"platform\": {{\"os\": \"linux\", \"architecture\": \"{}\"}}, \
+            \"annotations\": {{\"{}\": \"{}\"}}}}",
+            digest, architecture, REF_NAME_ANNOTATION, reference
+        )
+    }
+
+    #[tokio::test]
+    async fn oci_layout_tags() {
+        let dir = TempDir::new().unwrap();
+        std::fs::write(
+            dir.path().join("index.json"),
+            format!(
+                "{{\"schemaVersion\": 2, \"manifests\": [{}, {}, {}]}}",
+                manifest("python:3.11", "sha256:a1", "amd64"),
+                manifest("docker.io/library/python:3.11", "sha256:a2", "arm64"),
+                manifest("node:20", "sha256:b", "amd64"),
+            ),
+        )
+        .unwrap();
+        let image_dir = dir.path().join("docker.io/library/python");
+        std::fs::create_dir_all(&image_dir).unwrap();
+        std::fs::write(
+            image_dir.join("index.json"),
+            format!(
+                "{{\"schemaVersion\": 2, \"manifests\": [{}]}}",
+                manifest("3.12", "sha256:c", "amd64")
+            ),
+        )
+        .unwrap();
+        let source = OciLayoutTagSource::new(dir.path());
+        let tags = source.tags(&ImageRef::parse("python")).await.unwrap();
+        assert_eq!(
+            tags.iter()
+                .map(|tag| tag.name().as_str())
+                .collect::<Vec<_>>(),
+            vec!["3.11", "3.12"]
+        );
+        assert_eq!(tags[0].digest(), &Some("sha256:a1".to_owned()));
+        assert_eq!(tags[0].platforms().len(), 2);
+        assert!(source.tags(&ImageRef::parse("ghcr.io/a/b")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn snapshot_formats() {
+        let python = ImageRef::parse("python");
+        for (data, count) in [
+            ("{\"python\": [\"3.11\", \"3.12\"]}", 2),
+            (
+                "{\"python\": [\"3.11\", {\"name\": \"3.12\", \"digest\": \"sha256:a\"}]}",
+                2,
+            ),
+            (
+                "{\"Repository\": \"docker.io/library/python\", \"Tags\": [\"3.11\"]}",
+                1,
+            ),
+            (
+                "[{\"Repository\": \"docker.io/library/python\", \"Tags\": [\"3.11\"]}]",
+                1,
+            ),
+        ] {
+            let source = StaticTagSource::from_snapshot(data).unwrap();
+            assert_eq!(source.tags(&python).await.unwrap().len(), count, "{}", data);
+        }
+        assert!(StaticTagSource::from_snapshot("{\"python\": 3}").is_err());
+    }
+}
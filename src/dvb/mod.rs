@@ -1,5 +1,18 @@
+pub mod api;
+pub mod auth;
+pub mod cache;
+pub mod client;
+pub mod config;
+pub mod constraint;
 pub mod convert;
 pub mod data;
+pub mod discover;
+pub mod local;
+pub mod parse;
+pub mod policy;
+pub mod pragma;
 pub mod read;
+pub mod registry;
+pub mod source;
 pub mod uptag;
 pub mod write;
@@ -0,0 +1,384 @@
+use ::std::fmt;
+use ::std::mem;
+use ::std::ops::Range;
+
+use ::derive_getters::Getters;
+use ::lazy_static::lazy_static;
+use ::regex::Regex;
+
+lazy_static! {
+    static ref DIRECTIVE_RE: Regex =
+        Regex::new(r"^#\s*([a-zA-Z][a-zA-Z0-9]*)\s*=\s*(.*?)\s*$").unwrap();
+    static ref HEREDOC_RE: Regex =
+        Regex::new(r#"<<(-?)(["']?)([A-Za-z_][A-Za-z0-9_]*)["']?"#).unwrap();
+}
+
+/// Instructions whose heredocs (`<<EOF`) are read as part of the instruction.
+static HEREDOC_KEYWORDS: [&str; 3] = ["RUN", "COPY", "ADD"];
+
+/// A whitespace-separated word in the arguments of an instruction, with its byte range in the Dockerfile.
+#[derive(Debug, Clone, PartialEq, Eq, Getters)]
+pub struct Word {
+    text: String,
+    span: Range<usize>,
+}
+
+impl Word {
+    /// Whether the word appears as-is at its span, rather than being split by a line continuation.
+    pub fn is_contiguous(&self) -> bool {
+        self.text.len() == self.span.len()
+    }
+}
+
+/// An instruction like `FROM` or `RUN`, which can span multiple lines through line continuations or heredocs.
+#[derive(Debug, Clone, PartialEq, Eq, Getters)]
+pub struct Instruction {
+    /// Keyword in upper case, like `FROM`.
+    keyword: String,
+    /// Arguments after the keyword, with line continuations removed.
+    args: String,
+    /// Words in the arguments.
+    words: Vec<Word>,
+    /// Byte range from the keyword to the end of the last line (without line break), including heredocs.
+    span: Range<usize>,
+    /// Line number of the keyword, starting at 1.
+    line: usize,
+    /// Text of the comment lines directly above the instruction, without `#`.
+    comments: Vec<String>,
+}
+
+/// A build stage, which starts at a `FROM` instruction.
+#[derive(Debug, Clone, PartialEq, Eq, Getters)]
+pub struct Stage {
+    /// Image or earlier stage that the stage is built on, as written after `FROM`.
+    base: String,
+    kind: BaseKind,
+    /// Name given with `AS`, which later stages can refer to.
+    alias: Option<String>,
+    /// Index of the `FROM` instruction.
+    instruction: usize,
+}
+
+/// What a build stage is based on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BaseKind {
+    /// An image from a registry, like `python:3.11`.
+    Image,
+    /// An earlier stage in the same Dockerfile, referred to by its alias.
+    Stage,
+    /// The empty `scratch` image.
+    Scratch,
+}
+
+impl fmt::Display for BaseKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            BaseKind::Image => "image",
+            BaseKind::Stage => "stage",
+            BaseKind::Scratch => "scratch",
+        })
+    }
+}
+
+/// A line without its line break (`\n` or `\r\n`).
+struct Line {
+    start: usize,
+    end: usize,
+}
+
+impl Line {
+    fn text<'a>(&self, content: &'a str) -> &'a str {
+        &content[self.start..self.end]
+    }
+
+    fn is_blank_or_comment(&self, content: &str) -> bool {
+        let text = self.text(content).trim_start();
+        text.is_empty() || text.starts_with('#')
+    }
+}
+
+fn split_lines(content: &str) -> Vec<Line> {
+    let mut lines = vec![];
+    let mut start = 0;
+    for (index, _) in content.match_indices('\n') {
+        let end = if content[start..index].ends_with('\r') {
+            index - 1
+        } else {
+            index
+        };
+        lines.push(Line { start, end });
+        start = index + 1;
+    }
+    if start < content.len() {
+        lines.push(Line {
+            start,
+            end: content.len(),
+        });
+    }
+    lines
+}
+
+/// The escape character from a `# escape=` parser directive at the top, or `\` by default.
+fn escape_char(content: &str, lines: &[Line]) -> char {
+    lines
+        .iter()
+        .map_while(|line| DIRECTIVE_RE.captures(line.text(content)))
+        .find(|directive| directive[1].eq_ignore_ascii_case("escape"))
+        .and_then(|directive| directive[2].chars().next())
+        .unwrap_or('\\')
+}
+
+/// Split a Dockerfile into instructions. Comments and blank lines are skipped, also inside line continuations,
+/// and keywords are case-insensitive. Heredocs of `RUN`, `COPY` and `ADD` belong to their instruction,
+/// so that their content is not mistaken for instructions.
+pub fn parse_instructions(content: &str) -> Vec<Instruction> {
+    let lines = split_lines(content);
+    let escape = escape_char(content, &lines);
+    let mut instructions = vec![];
+    let mut comments = vec![];
+    let mut index = 0;
+    while index < lines.len() {
+        if lines[index].is_blank_or_comment(content) {
+            match lines[index].text(content).trim().strip_prefix('#') {
+                Some(comment) => comments.push(comment.trim().to_owned()),
+                None => comments.clear(),
+            }
+            index += 1;
+            continue;
+        }
+        let line_nr = index + 1;
+        let text = lines[index].text(content);
+        let start = lines[index].start + (text.len() - text.trim_start().len());
+        let mut end;
+        // The text without continuations, and for each byte its position in the content.
+        let mut joined = String::new();
+        let mut origins = vec![];
+        let mut part_start = start;
+        loop {
+            let line = &lines[index];
+            end = line.end;
+            index += 1;
+            let part = content[part_start..line.end].trim_end();
+            let is_continued = part.ends_with(escape);
+            let part_end = if is_continued {
+                part_start + part.len() - escape.len_utf8()
+            } else {
+                line.end
+            };
+            joined.push_str(&content[part_start..part_end]);
+            origins.extend(part_start..part_end);
+            if !is_continued {
+                break;
+            }
+            while index < lines.len() && lines[index].is_blank_or_comment(content) {
+                index += 1;
+            }
+            if index >= lines.len() {
+                break;
+            }
+            part_start = lines[index].start;
+        }
+        let mut words = split_words(&joined, &origins);
+        if words.is_empty() {
+            comments.clear();
+            continue;
+        }
+        let keyword_word = words.remove(0);
+        let keyword = keyword_word.text.to_ascii_uppercase();
+        let args_start = keyword_word.text.len();
+        let args = joined[args_start..].trim().to_owned();
+        if HEREDOC_KEYWORDS.contains(&keyword.as_str()) {
+            for heredoc in HEREDOC_RE.captures_iter(&joined) {
+                let strip_tabs = !heredoc[1].is_empty();
+                let terminator = &heredoc[3];
+                while index < lines.len() {
+                    let line = lines[index].text(content);
+                    end = lines[index].end;
+                    index += 1;
+                    let line = if strip_tabs {
+                        line.trim_start_matches('\t')
+                    } else {
+                        line
+                    };
+                    if line == terminator {
+                        break;
+                    }
+                }
+            }
+        }
+        instructions.push(Instruction {
+            keyword,
+            args,
+            words,
+            span: start..end,
+            line: line_nr,
+            comments: mem::take(&mut comments),
+        });
+    }
+    instructions
+}
+
+fn split_words(joined: &str, origins: &[usize]) -> Vec<Word> {
+    let mut words = vec![];
+    let mut word_start = None;
+    for (index, chr) in joined.char_indices().chain(Some((joined.len(), ' '))) {
+        match (word_start, chr.is_whitespace()) {
+            (None, false) => word_start = Some(index),
+            (Some(start), true) => {
+                words.push(Word {
+                    text: joined[start..index].to_owned(),
+                    span: origins[start]..origins[index - 1] + 1,
+                });
+                word_start = None;
+            }
+            _ => {}
+        }
+    }
+    words
+}
+
+/// The build stages, one for each `FROM` instruction. A base is only a stage reference if an earlier
+/// stage has that alias (case-insensitive), otherwise it is an image, like Docker does.
+pub fn find_stages(instructions: &[Instruction]) -> Vec<Stage> {
+    let mut stages: Vec<Stage> = vec![];
+    for (index, instruction) in instructions.iter().enumerate() {
+        if instruction.keyword != "FROM" {
+            continue;
+        }
+        let mut words = instruction
+            .words
+            .iter()
+            .skip_while(|word| word.text.starts_with("--"));
+        let Some(base) = words.next().map(|word| word.text.clone()) else {
+            continue;
+        };
+        let alias = match (words.next(), words.next()) {
+            (Some(as_word), Some(alias)) if as_word.text.eq_ignore_ascii_case("AS") => {
+                Some(alias.text.clone())
+            }
+            _ => None,
+        };
+        let kind = if base.eq_ignore_ascii_case("scratch") {
+            BaseKind::Scratch
+        } else if stages.iter().any(|stage| {
+            stage
+                .alias
+                .as_ref()
+                .is_some_and(|alias| alias.eq_ignore_ascii_case(&base))
+        }) {
+            BaseKind::Stage
+        } else {
+            BaseKind::Image
+        };
+        stages.push(Stage {
+            base,
+            kind,
+            alias,
+            instruction: index,
+        });
+    }
+    stages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keywords(instructions: &[Instruction]) -> Vec<&str> {
+        instructions
+            .iter()
+            .map(|instruction| instruction.keyword.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn case_and_whitespace() {
+        let content = "# comment\n  from python:3.11 as build\n\n\tRun echo hi\nFROM build\n";
+        let instructions = parse_instructions(content);
+        assert_eq!(keywords(&instructions), vec!["FROM", "RUN", "FROM"]);
+        assert_eq!(instructions[0].args, "python:3.11 as build");
+        assert_eq!(instructions[0].line, 2);
+        assert_eq!(
+            &content[instructions[0].span.clone()],
+            "from python:3.11 as build"
+        );
+        assert_eq!(
+            &content[instructions[0].words[0].span.clone()],
+            "python:3.11"
+        );
+        assert_eq!(instructions[1].line, 4);
+        assert_eq!(instructions[0].comments, vec!["comment"]);
+        assert!(instructions[1].comments.is_empty());
+    }
+
+    #[test]
+    fn line_continuations() {
+        let content = "FROM --platform=$BUILDPLATFORM \\\n    # comment\n\n    rust:1.80 \\\r\n  AS build\r\nRUN cargo \\\n  build\n";
+        let instructions = parse_instructions(content);
+        assert_eq!(keywords(&instructions), vec!["FROM", "RUN"]);
+        let from = &instructions[0];
+        assert_eq!(
+            from.words
+                .iter()
+                .map(|word| word.text.as_str())
+                .collect::<Vec<_>>(),
+            vec!["--platform=$BUILDPLATFORM", "rust:1.80", "AS", "build"]
+        );
+        assert!(from.words.iter().all(|word| word.is_contiguous()));
+        assert_eq!(&content[from.words[1].span.clone()], "rust:1.80");
+        assert!(content[from.span.clone()].ends_with("AS build"));
+        assert_eq!(instructions[1].line, 6);
+        assert_eq!(instructions[1].args, "cargo   build");
+    }
+
+    #[test]
+    fn split_word() {
+        let content = "FROM pyth\\\non:3.11\n";
+        let instructions = parse_instructions(content);
+        assert_eq!(instructions[0].words[0].text, "python:3.11");
+        assert!(!instructions[0].words[0].is_contiguous());
+    }
+
+    #[test]
+    fn heredocs() {
+        let content = "FROM alpine:3.20\nRUN <<EOF cat > /a && <<-'END' cat > /b\nFROM python:3.11\nEOF\n\tFROM node:20\n\tEND\nCOPY <<EOF /c\nFROM x:1\nEOF\nfrom alpine:3.20 AS final";
+        let instructions = parse_instructions(content);
+        assert_eq!(keywords(&instructions), vec!["FROM", "RUN", "COPY", "FROM"]);
+        assert!(content[instructions[1].span.clone()].ends_with("\tEND"));
+        assert_eq!(instructions[3].line, 10);
+    }
+
+    #[test]
+    fn escape_directive() {
+        let content = "# escape=`\nFROM mcr.microsoft.com/windows/servercore:ltsc2022 `\n  AS build\nRUN dir C:\\\nFROM build";
+        let instructions = parse_instructions(content);
+        assert_eq!(keywords(&instructions), vec!["FROM", "RUN", "FROM"]);
+        assert_eq!(instructions[0].words.len(), 3);
+        assert_eq!(instructions[1].args, "dir C:\\");
+    }
+
+    #[test]
+    fn stages_and_aliases() {
+        let content = "FROM --platform=linux/amd64 rust:1.80 AS build\n\
+            RUN make\n\
+            FROM Build as test\n\
+            FROM final\n\
+            FROM scratch AS final\n";
+        let stages = find_stages(&parse_instructions(content));
+        let stage = |base: &str, kind: BaseKind, alias: Option<&str>, instruction: usize| Stage {
+            base: base.to_owned(),
+            kind,
+            alias: alias.map(|alias| alias.to_owned()),
+            instruction,
+        };
+        assert_eq!(
+            stages,
+            vec![
+                stage("rust:1.80", BaseKind::Image, Some("build"), 0),
+                stage("Build", BaseKind::Stage, Some("test"), 2),
+                stage("final", BaseKind::Image, None, 3),
+                stage("scratch", BaseKind::Scratch, Some("final"), 4),
+            ]
+        );
+    }
+}
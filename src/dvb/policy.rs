@@ -0,0 +1,292 @@
+use ::std::collections::HashMap;
+use ::std::fmt;
+use ::std::str::FromStr;
+use ::std::time::Duration;
+
+use ::chrono::{DateTime, Utc};
+use ::itertools::Itertools;
+
+use crate::dvb::constraint::VersionConstraint;
+use crate::dvb::data::{Parent, Platform, Tag, TagInfo};
+use crate::dvb::registry::ImageRef;
+
+/// Which version numbers may change when bumping. Components are counted from the left, as in the
+/// current tag, so for `3.11` the minor component is 11 and a patch bump cannot change anything.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum BumpLevel {
+    /// Only change the third and later components, like `3.11.2` to `3.11.9`.
+    Patch,
+    /// Only change the second and later components, like `3.11.2` to `3.13.0`.
+    #[default]
+    Minor,
+    /// Change any component, like `3.11.2` to `4.0.1`, which might be incompatible.
+    Major,
+}
+
+impl BumpLevel {
+    /// Whether bumping from the current tag to the candidate stays within this level.
+    pub fn allows(&self, current: &Tag, candidate: &Tag) -> bool {
+        let fixed = match self {
+            BumpLevel::Patch => 2,
+            BumpLevel::Minor => 1,
+            BumpLevel::Major => 0,
+        };
+        components(current)[..fixed] == components(candidate)[..fixed]
+    }
+}
+
+fn components(tag: &Tag) -> [u32; 4] {
+    let (nr1, nr2, nr3, nr4) = *tag.nrs();
+    [nr1, nr2, nr3, nr4]
+}
+
+impl FromStr for BumpLevel {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text.to_ascii_lowercase().as_str() {
+            "patch" => Ok(BumpLevel::Patch),
+            "minor" => Ok(BumpLevel::Minor),
+            "major" => Ok(BumpLevel::Major),
+            _ => Err(format!(
+                "invalid bump level '{}', expected patch, minor or major",
+                text
+            )),
+        }
+    }
+}
+
+impl fmt::Display for BumpLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BumpLevel::Patch => write!(f, "patch"),
+            BumpLevel::Minor => write!(f, "minor"),
+            BumpLevel::Major => write!(f, "major"),
+        }
+    }
+}
+
+/// Rules for which tags images may be bumped to.
+#[derive(Debug, Clone, Default)]
+pub struct BumpPolicy {
+    /// Which version numbers may change, unless overridden for the image.
+    pub level: BumpLevel,
+    /// Bump levels for specific images, by image name like `python` or `ghcr.io/owner/image`.
+    pub image_levels: HashMap<String, BumpLevel>,
+    /// Version constraints for specific images, by image name, on top of the bump level.
+    pub constraints: HashMap<String, VersionConstraint>,
+    /// Also bump to pre-releases like `3.14.0rc1`, which are otherwise only chosen if the current tag is a
+    /// pre-release of the same version.
+    pub pre_release: bool,
+    /// Only bump to tags that are available for all these platforms.
+    pub platforms: Vec<Platform>,
+    /// Only bump to tags that were pushed at least this long ago, so that tags that are
+    /// quickly replaced or removed are not picked up. Tags without a push time are allowed.
+    pub min_age: Duration,
+}
+
+impl BumpPolicy {
+    /// The bump level for the parent, which is the default level unless overridden by a pragma or for the image.
+    pub fn level_for(&self, parent: &Parent) -> BumpLevel {
+        parent
+            .pragmas()
+            .level()
+            .or_else(|| for_image(&self.image_levels, parent).copied())
+            .unwrap_or(self.level)
+    }
+
+    /// The version constraint for the parent from a pragma or for the image, if any.
+    pub fn constraint_for<'a>(&'a self, parent: &'a Parent) -> Option<&'a VersionConstraint> {
+        parent
+            .pragmas()
+            .constraint()
+            .as_ref()
+            .or_else(|| for_image(&self.constraints, parent))
+    }
+
+    /// Platforms that a new tag must be available for, including the one from a `--platform` flag of the parent.
+    pub fn platforms_for(&self, parent: &Parent) -> Vec<Platform> {
+        self.platforms
+            .iter()
+            .cloned()
+            .chain(parent.platform())
+            .unique()
+            .collect()
+    }
+
+    /// Whether the tag may be chosen as far as pre-releases are concerned. Without `pre_release`, only
+    /// final releases are allowed, or later pre-releases of the same version if the current tag is a pre-release.
+    pub fn allows_pre_release(&self, current: &Tag, candidate: &Tag) -> bool {
+        self.pre_release
+            || !candidate.is_pre_release()
+            || (current.is_pre_release() && current.nrs() == candidate.nrs())
+    }
+
+    /// Describe why a tag is too new to bump to, if it was pushed less than `min_age` before `now`.
+    pub fn too_new(&self, info: &TagInfo, now: DateTime<Utc>) -> Option<String> {
+        let pushed = (*info.last_updated())?;
+        let age = (now - pushed).to_std().unwrap_or(Duration::ZERO);
+        if age >= self.min_age {
+            return None;
+        }
+        Some(format!(
+            "pushed {}, less than {} days ago",
+            pushed.format("%Y-%m-%d %H:%M"),
+            self.min_age.as_secs_f64() / 86400.0
+        ))
+    }
+}
+
+/// The setting for the image of the parent, with image names compared by repository, so `python`
+/// is the same as `docker.io/library/python`.
+fn for_image<'a, T>(settings: &'a HashMap<String, T>, parent: &Parent) -> Option<&'a T> {
+    let key = ImageRef::parse(parent.image_name()).repository_key();
+    settings
+        .iter()
+        .find(|(image, _)| ImageRef::parse(image).repository_key() == key)
+        .map(|(_, setting)| setting)
+}
+
+/// Required platforms that are not in the available ones.
+pub fn missing_platforms<'a>(
+    available: &[Platform],
+    required: &'a [Platform],
+) -> Vec<&'a Platform> {
+    required
+        .iter()
+        .filter(|required| {
+            !available
+                .iter()
+                .any(|platform| platform.satisfies(required))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use ::std::path::PathBuf;
+    use ::std::rc::Rc;
+
+    use ::regex::Regex;
+
+    use crate::dvb::data::Dockerfile;
+
+    use super::*;
+
+    fn platform(text: &str) -> Platform {
+        Platform::parse(text).unwrap()
+    }
+
+    #[test]
+    fn parse_platform() {
+        assert_eq!(
+            platform("linux/arm64/v8"),
+            Platform::new(
+                "linux".to_owned(),
+                "arm64".to_owned(),
+                Some("v8".to_owned())
+            )
+        );
+        assert_eq!(platform("linux/amd64").to_string(), "linux/amd64");
+        assert!(Platform::parse("linux").is_err());
+        assert!(Platform::parse("linux//v8").is_err());
+        assert!(Platform::parse("linux/arm/v7/x").is_err());
+    }
+
+    #[test]
+    fn missing_variant() {
+        let available = vec![platform("linux/amd64"), platform("linux/arm64/v8")];
+        assert!(missing_platforms(&available, &[platform("linux/arm64")]).is_empty());
+        assert!(missing_platforms(&available, &[platform("linux/arm64/v8")]).is_empty());
+        assert_eq!(
+            missing_platforms(
+                &available,
+                &[platform("linux/amd64"), platform("linux/arm/v7")]
+            ),
+            vec![&platform("linux/arm/v7")]
+        );
+    }
+
+    fn tag(name: &str, nrs: (u32, u32, u32, u32)) -> Tag {
+        Tag::new(name.to_owned(), nrs)
+    }
+
+    #[test]
+    fn bump_levels() {
+        let current = tag("3.11.2", (3, 11, 2, 0));
+        let patch = tag("3.11.9", (3, 11, 9, 0));
+        let minor = tag("3.13.0", (3, 13, 0, 0));
+        let major = tag("4.0.1", (4, 0, 1, 0));
+        assert!(BumpLevel::Patch.allows(&current, &patch));
+        assert!(!BumpLevel::Patch.allows(&current, &minor));
+        assert!(BumpLevel::Minor.allows(&current, &minor));
+        assert!(!BumpLevel::Minor.allows(&current, &major));
+        assert!(BumpLevel::Major.allows(&current, &major));
+        let short = tag("3.11", (3, 11, 0, 0));
+        assert!(!BumpLevel::Patch.allows(&short, &tag("3.12", (3, 12, 0, 0))));
+        assert!(BumpLevel::Minor.allows(&short, &tag("3.12", (3, 12, 0, 0))));
+        assert_eq!("Major".parse::<BumpLevel>(), Ok(BumpLevel::Major));
+        assert!("breaking".parse::<BumpLevel>().is_err());
+    }
+
+    #[test]
+    fn cooldown() {
+        let now = "2024-10-10T12:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        let pushed = |time: Option<&str>| {
+            TagInfo::new("3.13".to_owned()).with_metadata(
+                time.map(|time| time.parse().unwrap()),
+                None,
+                vec![],
+            )
+        };
+        let policy = BumpPolicy {
+            min_age: Duration::from_secs(3 * 86400),
+            ..BumpPolicy::default()
+        };
+        assert_eq!(
+            policy.too_new(&pushed(Some("2024-10-08T01:23:45Z")), now),
+            Some("pushed 2024-10-08 01:23, less than 3 days ago".to_owned())
+        );
+        assert_eq!(
+            policy.too_new(&pushed(Some("2024-10-07T01:23:45Z")), now),
+            None
+        );
+        assert_eq!(policy.too_new(&pushed(None), now), None);
+        assert_eq!(
+            BumpPolicy::default().too_new(&pushed(Some("2024-10-10T12:00:00Z")), now),
+            None
+        );
+    }
+
+    #[test]
+    fn platform_from_flag() {
+        let dockerfile = Rc::new(Dockerfile::new(PathBuf::from("Dockerfile"), "".to_owned()));
+        let parent = |flags: &[&str]| {
+            Parent::new(
+                dockerfile.clone(),
+                "python".to_owned(),
+                Regex::new("^([0-9]+)$").unwrap(),
+                Tag::new("3".to_owned(), (3, 0, 0, 0)),
+                "".to_owned(),
+            )
+            .with_flags(flags.iter().map(|flag| flag.to_string()).collect())
+        };
+        let policy = BumpPolicy {
+            platforms: vec![platform("linux/amd64")],
+            image_levels: HashMap::from([(
+                "docker.io/library/python".to_owned(),
+                BumpLevel::Patch,
+            )]),
+            ..BumpPolicy::default()
+        };
+        assert_eq!(policy.level_for(&parent(&[])), BumpLevel::Patch);
+        assert_eq!(
+            policy.platforms_for(&parent(&["--platform=linux/arm64"])),
+            vec![platform("linux/amd64"), platform("linux/arm64")]
+        );
+        assert_eq!(
+            policy.platforms_for(&parent(&["--platform=$BUILDPLATFORM"])),
+            vec![platform("linux/amd64")]
+        );
+    }
+}
@@ -0,0 +1,113 @@
+use ::derive_getters::Getters;
+use ::lazy_static::lazy_static;
+use ::regex::Regex;
+
+use crate::dvb::constraint::VersionConstraint;
+use crate::dvb::policy::BumpLevel;
+
+lazy_static! {
+    static ref PRAGMA_RE: Regex = Regex::new(r"^dvb:([a-z-]*)\s*(.*)$").unwrap();
+}
+
+/// Settings for a single FROM line, from comments like `# dvb:ignore` directly above it.
+/// These take precedence over the settings for the image.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Getters)]
+pub struct Pragmas {
+    /// Do not bump this line (`dvb:ignore`).
+    ignore: bool,
+    /// Bump level for this line (`dvb:allow-major` or `dvb:level patch`).
+    level: Option<BumpLevel>,
+    /// Version constraint for this line, like `dvb:constraint <3.13`.
+    constraint: Option<VersionConstraint>,
+}
+
+impl Pragmas {
+    /// Read the `dvb:` pragmas from comments, ignoring other comments. Pragmas that are unknown or invalid
+    /// are skipped, and returned as problems so they can be reported.
+    pub fn parse(comments: &[String]) -> (Pragmas, Vec<String>) {
+        let mut pragmas = Pragmas::default();
+        let mut problems = vec![];
+        for comment in comments {
+            if let Err(problem) = pragmas.apply(comment) {
+                problems.push(problem);
+            }
+        }
+        (pragmas, problems)
+    }
+
+    fn apply(&mut self, comment: &str) -> Result<(), String> {
+        let Some(pragma) = PRAGMA_RE.captures(comment) else {
+            return Ok(());
+        };
+        let argument = pragma[2].trim();
+        match (&pragma[1], argument.is_empty()) {
+            ("ignore", true) => self.ignore = true,
+            ("allow-major", true) => self.level = Some(BumpLevel::Major),
+            ("level", false) => self.level = Some(argument.parse()?),
+            ("constraint", false) => self.constraint = Some(argument.parse()?),
+            ("ignore" | "allow-major", false) => {
+                return Err(format!("pragma '{}' does not take an argument", comment))
+            }
+            ("level" | "constraint", true) => {
+                return Err(format!("pragma '{}' needs an argument", comment))
+            }
+            _ => {
+                return Err(format!(
+                    "unknown pragma '{}', expected dvb:ignore, dvb:allow-major, dvb:level or dvb:constraint",
+                    comment
+                ))
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(comments: &[&str]) -> (Pragmas, Vec<String>) {
+        Pragmas::parse(
+            &comments
+                .iter()
+                .map(|comment| comment.to_string())
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    #[test]
+    fn parse_pragmas() {
+        assert_eq!(
+            parse(&["renovate: ignore", "see docs"]),
+            (Pragmas::default(), vec![])
+        );
+        let (pragmas, problems) = parse(&["dvb:ignore", "dvb:constraint >=3.11, <3.13"]);
+        assert!(problems.is_empty());
+        assert!(pragmas.ignore);
+        assert_eq!(pragmas.level, None);
+        assert_eq!(pragmas.constraint.unwrap().to_string(), ">=3.11, <3.13");
+        assert_eq!(parse(&["dvb:allow-major"]).0.level, Some(BumpLevel::Major));
+        assert_eq!(parse(&["dvb:level patch"]).0.level, Some(BumpLevel::Patch));
+    }
+
+    #[test]
+    fn skip_invalid() {
+        for invalid in [
+            "dvb:ignroe",
+            "dvb:ignore please",
+            "dvb:constraint",
+            "dvb:constraint 3.13",
+            "dvb:level huge",
+        ] {
+            let (pragmas, problems) = parse(&[invalid]);
+            assert_eq!(pragmas, Pragmas::default(), "{}", invalid);
+            assert_eq!(problems.len(), 1, "{}", invalid);
+        }
+        let (pragmas, problems) = parse(&["dvb:level huge", "dvb:allow-major"]);
+        assert_eq!(pragmas.level, Some(BumpLevel::Major));
+        assert_eq!(
+            problems,
+            vec!["invalid bump level 'huge', expected patch, minor or major".to_owned()]
+        );
+    }
+}
@@ -1,21 +1,30 @@
+use ::std::collections::HashMap;
 use ::std::collections::HashSet;
+use ::std::ops::Range;
 use ::std::path::Path;
 use ::std::path::PathBuf;
 use ::std::rc::Rc;
 
 use ::futures::future::try_join_all;
+use ::itertools::Itertools;
 use ::lazy_static::lazy_static;
-use ::log::{info, warn};
+use ::log::{debug, info, warn};
 use ::regex::Regex;
 use ::tokio::fs::read_to_string;
 
 use crate::dvb::convert::{parse_tag, tag_to_re};
+use crate::dvb::data::TagArg;
+use crate::dvb::parse::{BaseKind, Instruction};
+use crate::dvb::pragma::Pragmas;
 use crate::Parent;
 
 use super::data::Dockerfile;
 
 lazy_static! {
-    static ref FROM_RE: Regex = Regex::new(r"^FROM\s+(\S+):(\S+)\s*( .*)?$").unwrap();
+    static ref ARG_RE: Regex =
+        Regex::new(r#"^([A-Za-z_][A-Za-z0-9_]*)=(?:"([^"]*)"|'([^']*)'|([^"'\s]*))$"#).unwrap();
+    static ref ARG_REF_RE: Regex =
+        Regex::new(r"\$\{([A-Za-z_][A-Za-z0-9_]*)\}|\$([A-Za-z_][A-Za-z0-9_]*)").unwrap();
 }
 
 pub async fn read_all_dockerfiles(dockerfiles: &[PathBuf]) -> Result<Vec<Rc<Dockerfile>>, String> {
@@ -46,74 +55,219 @@ async fn read_dockerfile(path: &Path) -> Result<Dockerfile, String> {
 pub fn extract_parents(dockerfiles: &[Rc<Dockerfile>]) -> Result<HashSet<Parent>, String> {
     dockerfiles
         .iter()
-        .flat_map(|file| file.content().lines().map(|line| (file.clone(), line)))
-        .filter(|(_, line)| line.starts_with("FROM "))
-        .map(|(file, line)| parse_line_from(file, line))
+        .flat_map(|file| {
+            let global_args = extract_global_args(file.instructions());
+            file.stages()
+                .iter()
+                .filter(|stage| {
+                    let is_image = *stage.kind() == BaseKind::Image;
+                    if !is_image {
+                        debug!("not bumping FROM {} ({})", stage.base(), stage.kind());
+                    }
+                    is_image
+                })
+                .map(move |stage| {
+                    let instruction = &file.instructions()[*stage.instruction()];
+                    parse_from(file.clone(), &global_args, instruction)
+                })
+        })
         .flat_map(|res_opt| res_opt.transpose().into_iter())
         //.inspect(|parent| debug!("found parent: {}", &parent))
         .collect()
 }
 
-fn parse_line_from(dockerfile: Rc<Dockerfile>, line: &str) -> Result<Option<Parent>, String> {
-    match FROM_RE.captures(line) {
-        Some(matches) => {
-            let name = matches[1].to_owned();
-            let tag_str = &matches[2];
-            let tag_pattern = tag_to_re(tag_str)?;
-            let tag = parse_tag(&tag_pattern, tag_str)?;
-            let suffix = matches
-                .get(3)
-                .map(|s| s.as_str())
-                .unwrap_or_else(|| "")
-                .to_owned();
-            Ok(Some(Parent::new(
-                dockerfile,
-                name,
-                tag_pattern,
-                tag,
-                suffix,
-            )))
-        }
-        None => {
-            if line.contains(':') {
-                warn!(
-                    "warning: FROM line, but could not recognize version:\n  {}",
-                    line
-                );
-            } else {
-                info!("skipping line because there is no version: {}", line);
-            }
-            Ok(None)
-        }
+/// Default value of a global `ARG`, with its byte range in the Dockerfile (without quotes).
+struct ArgDefault {
+    value: String,
+    span: Range<usize>,
+}
+
+/// Default values of `ARG`s declared before the first FROM, which are the only ones usable in FROM.
+fn extract_global_args(instructions: &[Instruction]) -> HashMap<String, ArgDefault> {
+    instructions
+        .iter()
+        .take_while(|instruction| instruction.keyword() != "FROM")
+        .filter(|instruction| instruction.keyword() == "ARG")
+        .flat_map(|instruction| instruction.words())
+        .filter(|word| word.is_contiguous())
+        .filter_map(|word| {
+            let matches = ARG_RE.captures(word.text())?;
+            let value = matches
+                .get(2)
+                .or_else(|| matches.get(3))
+                .or_else(|| matches.get(4))
+                .unwrap();
+            let start = word.span().start + value.start();
+            Some((
+                matches[1].to_owned(),
+                ArgDefault {
+                    value: value.as_str().to_owned(),
+                    span: start..start + value.len(),
+                },
+            ))
+        })
+        .collect()
+}
+
+/// Split an image reference like `python:3.11-slim@sha256:...` into name, tag and digest.
+/// A colon only starts the tag if it comes after the last slash, so that registry ports are part of the name.
+fn split_image_ref(image_ref: &str) -> (&str, Option<&str>, Option<&str>) {
+    let (image_ref, digest) = match image_ref.split_once('@') {
+        Some((image_ref, digest)) => (image_ref, Some(digest)),
+        None => (image_ref, None),
+    };
+    match image_ref.rsplit_once(':') {
+        Some((name, tag)) if !tag.contains('/') => (name, Some(tag), digest),
+        _ => (image_ref, None, digest),
+    }
+}
+
+/// Substitute a global `ARG` in the tag, returning the resolved tag and the `ARG` it came from.
+/// Returns `None` if the tag contains `ARG`s that cannot be resolved.
+fn resolve_tag_arg(
+    tag_template: &str,
+    global_args: &HashMap<String, ArgDefault>,
+) -> Option<(String, Option<TagArg>)> {
+    let mut refs = ARG_REF_RE.captures_iter(tag_template);
+    let Some(arg_ref) = refs.next() else {
+        return Some((tag_template.to_owned(), None));
+    };
+    if refs.next().is_some() {
+        warn!(
+            "warning: FROM tag refers to multiple ARGs, only one is supported: {}",
+            tag_template
+        );
+        return None;
     }
+    let arg_name = arg_ref.get(1).or_else(|| arg_ref.get(2)).unwrap().as_str();
+    let Some(ArgDefault { value, .. }) = global_args.get(arg_name) else {
+        warn!(
+            "warning: FROM tag refers to ARG {} which has no default before the first FROM: {}",
+            arg_name, tag_template
+        );
+        return None;
+    };
+    let whole_ref = arg_ref.get(0).unwrap();
+    let prefix = &tag_template[..whole_ref.start()];
+    let postfix = &tag_template[whole_ref.end()..];
+    Some((
+        format!("{}{}{}", prefix, value, postfix),
+        Some(TagArg::new(
+            arg_name.to_owned(),
+            value.to_owned(),
+            prefix.to_owned(),
+            postfix.to_owned(),
+        )),
+    ))
+}
+
+fn parse_from(
+    dockerfile: Rc<Dockerfile>,
+    global_args: &HashMap<String, ArgDefault>,
+    instruction: &Instruction,
+) -> Result<Option<Parent>, String> {
+    let (pragmas, problems) = Pragmas::parse(instruction.comments());
+    for problem in problems {
+        warn!(
+            "warning: skipping {} above line {} of {}",
+            problem,
+            instruction.line(),
+            dockerfile.path().to_string_lossy()
+        );
+    }
+    let mut words = instruction.words().iter();
+    let flags = words
+        .take_while_ref(|word| word.text().starts_with("--"))
+        .map(|flag| flag.text().to_owned())
+        .collect::<Vec<_>>();
+    let Some(image_word) = words.next() else {
+        return Ok(None);
+    };
+    let suffix = words
+        .map(|word| format!(" {}", word.text()))
+        .collect::<String>();
+    let image_ref = image_word.text();
+    let (name, tag, digest) = split_image_ref(image_ref);
+    if name.contains('$') {
+        info!(
+            "skipping line because the image name uses ARGs: {}",
+            instruction.args()
+        );
+        return Ok(None);
+    }
+    let Some(tag) = tag else {
+        info!(
+            "skipping line because there is no version: {}",
+            instruction.args()
+        );
+        return Ok(None);
+    };
+    if !image_word.is_contiguous() {
+        warn!(
+            "warning: skipping FROM on line {} because the image is split over multiple lines: {}",
+            instruction.line(),
+            image_ref
+        );
+        return Ok(None);
+    }
+    let Some((tag_str, tag_arg)) = resolve_tag_arg(tag, global_args) else {
+        return Ok(None);
+    };
+    let tag_pattern = tag_to_re(&tag_str)?;
+    let tag_start = image_word.span().start + name.len() + 1;
+    let tag_span = match &tag_arg {
+        Some(tag_arg) => global_args[tag_arg.name()].span.clone(),
+        None => tag_start..tag_start + tag.len(),
+    };
+    let digest_span =
+        digest.map(|digest| image_word.span().end - digest.len()..image_word.span().end);
+    let tag = parse_tag(&tag_pattern, tag_str)?;
+    let mut parent = Parent::new(dockerfile, name.to_owned(), tag_pattern, tag, suffix)
+        .with_flags(flags)
+        .with_spans(tag_span, digest_span)
+        .with_pragmas(pragmas);
+    if let Some(tag_arg) = tag_arg {
+        parent = parent.with_tag_arg(tag_arg);
+    }
+    if let Some(digest) = digest {
+        parent = parent.with_digest(digest.to_owned());
+    }
+    Ok(Some(parent))
 }
 
 #[cfg(test)]
 mod tests {
-    use ::regex::Regex;
-
+    use crate::dvb::convert::PRE_RELEASE_PATTERN;
     use crate::dvb::data::Tag;
+    use crate::dvb::policy::{BumpLevel, BumpPolicy};
 
     use super::*;
 
-    #[test]
-    fn parse_from_version_date() {
-        let dockerfile = Rc::new(Dockerfile::new(PathBuf::from("file.ext"), "".to_owned()));
-        let parent = parse_line_from(
+    fn parse_line(line: &str) -> (Rc<Dockerfile>, Option<Parent>) {
+        let dockerfile = Rc::new(Dockerfile::new(PathBuf::from("file.ext"), line.to_owned()));
+        let parent = parse_from(
             dockerfile.clone(),
-            "FROM mverleg/rust_nightly_musl_base:2021-10-17_11",
+            &HashMap::new(),
+            &dockerfile.instructions()[0],
         )
-        .unwrap()
         .unwrap();
+        (dockerfile, parent)
+    }
+
+    #[test]
+    fn parse_from_version_date() {
+        let (dockerfile, parent) = parse_line("FROM mverleg/rust_nightly_musl_base:2021-10-17_11");
+        let parent = parent.unwrap();
         assert_eq!(
             parent,
             Parent::new(
                 dockerfile,
                 "mverleg/rust_nightly_musl_base".to_owned(),
-                Regex::new("").unwrap(),
+                tag_to_re("2021-10-17_11").unwrap(),
                 Tag::new("2021-10-17_11".to_owned(), (2021, 10, 17, 11)),
                 "".to_owned()
             )
+            .with_spans(36..49, None)
         );
         assert_eq!(
             parent.tag_pattern().as_str(),
@@ -123,23 +277,202 @@ mod tests {
 
     #[test]
     fn parse_from_version_as() {
-        let dockerfile = Rc::new(Dockerfile::new(PathBuf::from("file.ext"), "".to_owned()));
-        let parent = parse_line_from(dockerfile.clone(), "FROM node:lts-alpine3.14 AS editor")
-            .unwrap()
-            .unwrap();
+        let (dockerfile, parent) = parse_line("FROM node:lts-alpine3.14 AS editor");
+        let parent = parent.unwrap();
         assert_eq!(
             parent,
             Parent::new(
                 dockerfile,
                 "node".to_owned(),
-                Regex::new("").unwrap(),
+                tag_to_re("lts-alpine3.14").unwrap(),
                 Tag::new("lts-alpine3.14".to_owned(), (3, 14, 0, 0)),
-                "AS editor".to_owned()
+                " AS editor".to_owned()
             )
+            .with_spans(10..24, None)
         );
         assert_eq!(
             parent.tag_pattern().as_str(),
-            r"^lts\-alpine([0-9]+)\.([0-9]+)$"
+            format!(r"^lts\-alpine([0-9]+)\.([0-9]+){}$", PRE_RELEASE_PATTERN)
+        );
+    }
+
+    #[test]
+    fn parse_from_platform_flags() {
+        let (dockerfile, parent) =
+            parse_line("FROM --platform=$BUILDPLATFORM --some-flag=x rust:1.80 AS build");
+        let parent = parent.unwrap();
+        assert_eq!(
+            parent,
+            Parent::new(
+                dockerfile,
+                "rust".to_owned(),
+                tag_to_re("1.80").unwrap(),
+                Tag::new("1.80".to_owned(), (1, 80, 0, 0)),
+                " AS build".to_owned()
+            )
+            .with_spans(50..54, None)
         );
+        assert_eq!(
+            parent.flags(),
+            &vec![
+                "--platform=$BUILDPLATFORM".to_owned(),
+                "--some-flag=x".to_owned()
+            ]
+        );
+        assert_eq!(parent.suffix(), " AS build");
+    }
+
+    #[test]
+    fn parse_from_global_arg() {
+        let content = "ARG PYTHON_VERSION=3.11\n\
+            ARG OTHER\n\
+            FROM python:${PYTHON_VERSION}-slim AS build\n\
+            ARG PYTHON_VERSION=2.7\n\
+            FROM python:$PYTHON_VERSION\n";
+        let dockerfile = Rc::new(Dockerfile::new(
+            PathBuf::from("file.ext"),
+            content.to_owned(),
+        ));
+        let parents = extract_parents(&[dockerfile])
+            .unwrap()
+            .into_iter()
+            .sorted_by(|p1, p2| p1.tag().name().cmp(p2.tag().name()))
+            .collect::<Vec<_>>();
+        assert_eq!(parents.len(), 2);
+        assert_eq!(parents[0].tag().name(), "3.11");
+        assert_eq!(
+            parents[0].tag_arg(),
+            &Some(TagArg::new(
+                "PYTHON_VERSION".to_owned(),
+                "3.11".to_owned(),
+                "".to_owned(),
+                "".to_owned()
+            ))
+        );
+        assert_eq!(parents[1].tag().name(), "3.11-slim");
+        assert_eq!(
+            parents[1].tag_pattern().as_str(),
+            format!(r"^([0-9]+)\.([0-9]+){}\-slim$", PRE_RELEASE_PATTERN)
+        );
+        assert_eq!(
+            parents[1]
+                .tag_arg()
+                .as_ref()
+                .unwrap()
+                .value_for("3.13-slim"),
+            Some("3.13".to_owned())
+        );
+    }
+
+    #[test]
+    fn shared_arg_flavours_are_distinct() {
+        let dockerfiles = [Rc::new(Dockerfile::new(
+            PathBuf::from("file.ext"),
+            "ARG V=3.11\nFROM python:${V}-slim\nFROM python:${V}-alpine\n".to_owned(),
+        ))];
+        // Hashing is randomized, so check several sets.
+        for _ in 0..20 {
+            assert_eq!(extract_parents(&dockerfiles).unwrap().len(), 2);
+        }
+    }
+
+    #[test]
+    fn skip_unresolved_arg() {
+        let (_, parent) = parse_line("FROM python:${PYTHON_VERSION}");
+        assert!(parent.is_none());
+    }
+
+    #[test]
+    fn parse_from_digest() {
+        let (_, parent) = parse_line("FROM node:20.11.1-alpine@sha256:bf077f6b0d8d3f4e2a3f4bb7ee4e3ab4dbf6c7cf20fa0f9ef7cc86db0c3a3b6a AS build");
+        let parent = parent.unwrap();
+        assert_eq!(parent.image_name(), "node");
+        assert_eq!(parent.tag().name(), "20.11.1-alpine");
+        assert_eq!(
+            parent.tag_pattern().as_str(),
+            format!(
+                r"^([0-9]+)\.([0-9]+)\.([0-9]+){}\-alpine$",
+                PRE_RELEASE_PATTERN
+            )
+        );
+        assert_eq!(
+            parent.digest().as_deref(),
+            Some("sha256:bf077f6b0d8d3f4e2a3f4bb7ee4e3ab4dbf6c7cf20fa0f9ef7cc86db0c3a3b6a")
+        );
+        assert_eq!(parent.suffix(), " AS build");
+    }
+
+    #[test]
+    fn parse_dockerfile_syntax() {
+        let content = "arg BASE_VERSION=3.20 OTHER=\"x y\"\n\
+            \x20 from alpine:${BASE_VERSION} as base\n\
+            RUN <<EOF\n\
+            FROM python:3.11\n\
+            EOF\n\
+            FROM --platform=linux/arm64 \\\n\
+            \x20   localhost:5000/tools/rust:1.80 \\\n\
+            \x20   AS build\n\
+            FROM localhost:5000/tools/base\n\
+            FROM base\n";
+        let dockerfile = Rc::new(Dockerfile::new(
+            PathBuf::from("file.ext"),
+            content.to_owned(),
+        ));
+        let parents = extract_parents(&[dockerfile])
+            .unwrap()
+            .into_iter()
+            .sorted_by(|p1, p2| p1.image_name().cmp(p2.image_name()))
+            .collect::<Vec<_>>();
+        assert_eq!(parents.len(), 2);
+        assert_eq!(parents[0].image_name(), "alpine");
+        assert_eq!(parents[0].tag().name(), "3.20");
+        assert_eq!(parents[0].suffix(), " as base");
+        assert_eq!(parents[1].image_name(), "localhost:5000/tools/rust");
+        assert_eq!(parents[1].tag().name(), "1.80");
+        assert_eq!(
+            parents[1].flags(),
+            &vec!["--platform=linux/arm64".to_owned()]
+        );
+        assert_eq!(parents[1].suffix(), " AS build");
+    }
+
+    #[test]
+    fn attach_pragmas() {
+        let content = "# dvb:ignore\n\
+            FROM alpine:3.19\n\
+            # Python is pinned below 3.13 until the dependencies support it.\n\
+            # dvb:allow-major\n\
+            # dvb:constraint <3.13\n\
+            FROM python:3.11 AS build\n\
+            # dvb:constraint <22\n\
+            \n\
+            FROM node:20\n";
+        let dockerfile = Rc::new(Dockerfile::new(
+            PathBuf::from("file.ext"),
+            content.to_owned(),
+        ));
+        let parents = extract_parents(&[dockerfile])
+            .unwrap()
+            .into_iter()
+            .sorted_by(|p1, p2| p1.image_name().cmp(p2.image_name()))
+            .collect::<Vec<_>>();
+        assert!(parents[0].pragmas().ignore());
+        assert_eq!(parents[1].pragmas(), &Pragmas::default());
+        let policy = BumpPolicy::default();
+        assert_eq!(policy.level_for(&parents[2]), BumpLevel::Major);
+        assert_eq!(
+            policy.constraint_for(&parents[2]).unwrap().to_string(),
+            "<3.13"
+        );
+
+        let invalid = Rc::new(Dockerfile::new(
+            PathBuf::from("file.ext"),
+            "# dvb:constraint 3.13\n# dvb:ignore\nFROM python:3.11\n".to_owned(),
+        ));
+        let parents = extract_parents(&[invalid]).unwrap();
+        assert_eq!(parents.len(), 1);
+        let parent = parents.into_iter().next().unwrap();
+        assert!(parent.pragmas().ignore());
+        assert_eq!(parent.pragmas().constraint(), &None);
     }
 }
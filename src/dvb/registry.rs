@@ -0,0 +1,247 @@
+use ::std::env;
+use ::std::fmt;
+use ::std::path::PathBuf;
+use ::std::time::Duration;
+
+use ::derive_getters::Getters;
+
+/// Host names that all refer to Docker Hub.
+static DOCKER_HUB_HOSTS: [&str; 3] = ["docker.io", "index.docker.io", "registry-1.docker.io"];
+
+/// Namespace of official images on Docker Hub, like `python` or `node`.
+static OFFICIAL_NAMESPACE: &str = "library";
+
+/// Settings for how tags are requested from registries.
+#[derive(Debug, Clone)]
+pub struct RegistryOptions {
+    /// Directory to cache tag listings in, or no caching if `None`.
+    pub cache_dir: Option<PathBuf>,
+    /// Cached tag listings younger than this are used instead of requesting the registry.
+    pub cache_ttl: Duration,
+    /// Only use cached tag listings, failing if an image is not in the cache.
+    pub offline: bool,
+    /// How many repositories to request tags for at the same time.
+    pub concurrency: usize,
+    /// Stop following tag listing pages after this many pages, or follow all pages if `None`.
+    pub max_tag_pages: Option<usize>,
+    /// How often to retry requests that fail with a network error, rate limit (429) or server error.
+    pub max_retries: u32,
+    /// Delay before the first retry, which doubles for every next retry (plus some jitter).
+    pub retry_delay: Duration,
+    /// Do not retry if the registry asks to wait longer than this (`Retry-After` or a used up rate limit).
+    pub max_retry_delay: Duration,
+}
+
+impl Default for RegistryOptions {
+    fn default() -> Self {
+        RegistryOptions {
+            cache_dir: None,
+            cache_ttl: Duration::ZERO,
+            offline: false,
+            concurrency: 8,
+            max_tag_pages: None,
+            max_retries: 3,
+            retry_delay: Duration::from_millis(500),
+            max_retry_delay: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RegistryOptions {
+    /// The user cache directory, `$XDG_CACHE_HOME/dockerfile_version_bumper` or `~/.cache/dockerfile_version_bumper`.
+    pub fn default_cache_dir() -> Option<PathBuf> {
+        env::var("XDG_CACHE_HOME")
+            .ok()
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| {
+                env::var("HOME")
+                    .ok()
+                    .map(|home| PathBuf::from(home).join(".cache"))
+            })
+            .map(|dir| dir.join("dockerfile_version_bumper"))
+    }
+}
+
+/// The registry that hosts an image, which determines how tags are listed.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Registry {
+    /// Docker Hub, which has its own api for listing tags.
+    DockerHub,
+    /// Any registry that implements the OCI Distribution api, like ghcr.io, quay.io, Harbor or `localhost:5000`.
+    Oci { host: String },
+}
+
+impl Registry {
+    /// Base url of the OCI Distribution api, which is plain http only for local registries.
+    pub fn oci_base_url(host: &str) -> String {
+        let hostname = host.split(':').next().unwrap_or(host);
+        if hostname == "localhost" || hostname == "127.0.0.1" {
+            format!("http://{}", host)
+        } else {
+            format!("https://{}", host)
+        }
+    }
+}
+
+/// An image reference like `ghcr.io/user/image:1.2` split into its parts.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Getters)]
+pub struct ImageRef {
+    registry: Registry,
+    /// User or organization, `library` for official Docker Hub images, may be empty for other registries.
+    namespace: String,
+    repository: String,
+    tag: Option<String>,
+}
+
+impl ImageRef {
+    /// Like Docker, the first component is a registry host if it has a `.` or `:`, or is `localhost`.
+    /// The Docker Hub hosts like `docker.io` are all normalized to the same registry.
+    pub fn parse(reference: &str) -> ImageRef {
+        let reference = reference.split('@').next().unwrap_or(reference);
+        let (name, tag) = match reference.rsplit_once(':') {
+            Some((name, tag)) if !tag.contains('/') => (name, Some(tag.to_owned())),
+            _ => (reference, None),
+        };
+        let (registry, path) = match name.split_once('/') {
+            Some((first, rest))
+                if first.contains('.') || first.contains(':') || first == "localhost" =>
+            {
+                if DOCKER_HUB_HOSTS.contains(&first) {
+                    (Registry::DockerHub, rest)
+                } else {
+                    (
+                        Registry::Oci {
+                            host: first.to_owned(),
+                        },
+                        rest,
+                    )
+                }
+            }
+            _ => (Registry::DockerHub, name),
+        };
+        let (namespace, repository) = match (path.rsplit_once('/'), &registry) {
+            (Some((namespace, repository)), _) => (namespace.to_owned(), repository.to_owned()),
+            (None, Registry::DockerHub) => (OFFICIAL_NAMESPACE.to_owned(), path.to_owned()),
+            (None, Registry::Oci { .. }) => ("".to_owned(), path.to_owned()),
+        };
+        ImageRef {
+            registry,
+            namespace,
+            repository,
+            tag,
+        }
+    }
+
+    /// Identifies the repository regardless of tag, like `docker.io/library/python` or `ghcr.io/user/image`.
+    pub fn repository_key(&self) -> String {
+        match &self.registry {
+            Registry::DockerHub => format!("{}/{}", DOCKER_HUB_HOSTS[0], self.path()),
+            Registry::Oci { host } => format!("{}/{}", host, self.path()),
+        }
+    }
+
+    /// Path of the repository within the registry, like `library/python` or `user/image`.
+    pub fn path(&self) -> String {
+        if self.namespace.is_empty() {
+            self.repository.clone()
+        } else {
+            format!("{}/{}", &self.namespace, &self.repository)
+        }
+    }
+}
+
+impl fmt::Display for ImageRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Registry::Oci { host } = &self.registry {
+            write!(f, "{}/", host)?;
+        }
+        write!(f, "{}", self.path())?;
+        if let Some(tag) = &self.tag {
+            write!(f, ":{}", tag)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn docker_hub(namespace: &str, repository: &str, tag: Option<&str>) -> ImageRef {
+        ImageRef {
+            registry: Registry::DockerHub,
+            namespace: namespace.to_owned(),
+            repository: repository.to_owned(),
+            tag: tag.map(|tag| tag.to_owned()),
+        }
+    }
+
+    #[test]
+    fn parse_official() {
+        assert_eq!(
+            ImageRef::parse("python:3.11"),
+            docker_hub("library", "python", Some("3.11"))
+        );
+        assert_eq!(
+            ImageRef::parse("python"),
+            docker_hub("library", "python", None)
+        );
+    }
+
+    #[test]
+    fn parse_user_image() {
+        assert_eq!(
+            ImageRef::parse("mverleg/rust_nightly_musl_base:2021-10-17_11"),
+            docker_hub("mverleg", "rust_nightly_musl_base", Some("2021-10-17_11"))
+        );
+    }
+
+    #[test]
+    fn normalize_docker_hub_hosts() {
+        let expected = docker_hub("library", "python", Some("3.11"));
+        assert_eq!(ImageRef::parse("docker.io/python:3.11"), expected);
+        assert_eq!(ImageRef::parse("docker.io/library/python:3.11"), expected);
+        assert_eq!(ImageRef::parse("index.docker.io/python:3.11"), expected);
+        assert_eq!(
+            ImageRef::parse("registry-1.docker.io/library/python:3.11"),
+            expected
+        );
+        assert_eq!(expected.to_string(), "library/python:3.11");
+        assert_eq!(expected.repository_key(), "docker.io/library/python");
+    }
+
+    #[test]
+    fn parse_oci_hosts() {
+        let image = ImageRef::parse("ghcr.io/org/team/image:1.0@sha256:abcd");
+        assert_eq!(
+            image.registry(),
+            &Registry::Oci {
+                host: "ghcr.io".to_owned()
+            }
+        );
+        assert_eq!(image.namespace(), "org/team");
+        assert_eq!(image.repository(), "image");
+        assert_eq!(image.tag().as_deref(), Some("1.0"));
+        assert_eq!(image.to_string(), "ghcr.io/org/team/image:1.0");
+
+        let image = ImageRef::parse("localhost:5000/image");
+        assert_eq!(
+            image.registry(),
+            &Registry::Oci {
+                host: "localhost:5000".to_owned()
+            }
+        );
+        assert_eq!(image.path(), "image");
+        assert_eq!(image.tag(), &None);
+    }
+
+    #[test]
+    fn oci_base_url_scheme() {
+        assert_eq!(Registry::oci_base_url("quay.io"), "https://quay.io");
+        assert_eq!(
+            Registry::oci_base_url("localhost:5000"),
+            "http://localhost:5000"
+        );
+    }
+}
@@ -0,0 +1,660 @@
+use ::std::collections::HashMap;
+use ::std::future::Future;
+use ::std::sync::{Arc, Mutex};
+
+use ::async_trait::async_trait;
+use ::chrono::Utc;
+use ::lazy_static::lazy_static;
+use ::log::{debug, info, warn};
+use ::regex::Regex;
+use ::reqwest::header::{
+    HeaderMap, HeaderValue, ACCEPT, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED, LINK,
+};
+use ::reqwest::StatusCode;
+use ::serde::de::DeserializeOwned;
+
+use crate::dvb::api::{describe_error, DockerHubTagPage, OciManifest, OciPlatform, OciTagList};
+use crate::dvb::cache::{CachedResponse, TagCache};
+use crate::dvb::client::RegistryClient;
+use crate::dvb::data::{Platform, TagInfo};
+use crate::dvb::registry::{ImageRef, Registry, RegistryOptions};
+
+lazy_static! {
+    static ref LINK_NEXT_RE: Regex = Regex::new("<([^>]*)>\\s*;\\s*rel=\"?next\"?").unwrap();
+}
+
+static DOCKER_HUB_REGISTRY_URL: &str = "https://registry-1.docker.io";
+
+static MANIFEST_ACCEPT: &str = "application/vnd.oci.image.index.v1+json, \
+    application/vnd.docker.distribution.manifest.list.v2+json, \
+    application/vnd.oci.image.manifest.v1+json, \
+    application/vnd.docker.distribution.manifest.v2+json";
+
+/// Where the available tags of images come from, like a registry, a mirror or a fixed list.
+#[async_trait]
+pub trait TagSource: Send + Sync {
+    /// All tags in the repository of the image, with whatever metadata the source knows about.
+    async fn tags(&self, image: &ImageRef) -> Result<Vec<TagInfo>, String>;
+
+    /// Digest of the manifest (or index for multi-platform images) that a tag points to.
+    /// By default this is the digest from the tag listing.
+    async fn digest(&self, image: &ImageRef, tag: &str) -> Result<String, String> {
+        self.tags(image)
+            .await?
+            .into_iter()
+            .find(|info| info.name() == tag)
+            .and_then(|info| info.digest().clone())
+            .ok_or_else(|| format!("no digest known for {}:{}", image, tag))
+    }
+
+    /// Platforms that a tag is available for, empty if unknown.
+    /// By default these are the platforms from the tag listing.
+    async fn platforms(&self, image: &ImageRef, tag: &str) -> Result<Vec<Platform>, String> {
+        Ok(self
+            .tags(image)
+            .await?
+            .into_iter()
+            .find(|info| info.name() == tag)
+            .map(|info| info.platforms().clone())
+            .unwrap_or_default())
+    }
+
+    /// How many images to look up at the same time.
+    fn concurrency(&self) -> usize {
+        8
+    }
+}
+
+/// Tags from whichever registry hosts the image: Docker Hub or any OCI Distribution registry.
+#[derive(Debug)]
+pub struct RegistryTagSource {
+    docker_hub: DockerHubTagSource,
+    oci: OciTagSource,
+    concurrency: usize,
+}
+
+impl RegistryTagSource {
+    /// Both apis use the same client and cache, so tokens and rate limits are shared.
+    pub fn new(options: &RegistryOptions) -> Self {
+        let backend = Arc::new(RegistryBackend::new(options));
+        RegistryTagSource {
+            docker_hub: DockerHubTagSource {
+                backend: backend.clone(),
+            },
+            oci: OciTagSource { backend },
+            concurrency: options.concurrency,
+        }
+    }
+}
+
+#[async_trait]
+impl TagSource for RegistryTagSource {
+    async fn tags(&self, image: &ImageRef) -> Result<Vec<TagInfo>, String> {
+        match image.registry() {
+            Registry::DockerHub => self.docker_hub.tags(image).await,
+            Registry::Oci { .. } => self.oci.tags(image).await,
+        }
+    }
+
+    async fn digest(&self, image: &ImageRef, tag: &str) -> Result<String, String> {
+        match image.registry() {
+            Registry::DockerHub => self.docker_hub.digest(image, tag).await,
+            Registry::Oci { .. } => self.oci.digest(image, tag).await,
+        }
+    }
+
+    async fn platforms(&self, image: &ImageRef, tag: &str) -> Result<Vec<Platform>, String> {
+        match image.registry() {
+            Registry::DockerHub => self.docker_hub.platforms(image, tag).await,
+            Registry::Oci { .. } => self.oci.platforms(image, tag).await,
+        }
+    }
+
+    fn concurrency(&self) -> usize {
+        self.concurrency
+    }
+}
+
+/// Tags from the Docker Hub api, which has more metadata than the OCI Distribution api.
+#[derive(Debug)]
+pub struct DockerHubTagSource {
+    backend: Arc<RegistryBackend>,
+}
+
+impl DockerHubTagSource {
+    pub fn new(options: &RegistryOptions) -> Self {
+        DockerHubTagSource {
+            backend: Arc::new(RegistryBackend::new(options)),
+        }
+    }
+}
+
+#[async_trait]
+impl TagSource for DockerHubTagSource {
+    async fn tags(&self, image: &ImageRef) -> Result<Vec<TagInfo>, String> {
+        if let Registry::Oci { host } = image.registry() {
+            return Err(format!(
+                "{} is hosted on {}, not on Docker Hub",
+                image.path(),
+                host
+            ));
+        }
+        let backend = &self.backend;
+        backend
+            .load_tags(image, request_docker_hub_tags(backend, image))
+            .await
+    }
+
+    async fn digest(&self, image: &ImageRef, tag: &str) -> Result<String, String> {
+        self.backend.load_digest(image, tag).await
+    }
+
+    async fn platforms(&self, image: &ImageRef, tag: &str) -> Result<Vec<Platform>, String> {
+        self.backend.load_platforms(image, tag).await
+    }
+
+    fn concurrency(&self) -> usize {
+        self.backend.options.concurrency
+    }
+}
+
+/// Tags from the OCI Distribution api, which works for any registry, including Docker Hub.
+#[derive(Debug)]
+pub struct OciTagSource {
+    backend: Arc<RegistryBackend>,
+}
+
+impl OciTagSource {
+    pub fn new(options: &RegistryOptions) -> Self {
+        OciTagSource {
+            backend: Arc::new(RegistryBackend::new(options)),
+        }
+    }
+}
+
+#[async_trait]
+impl TagSource for OciTagSource {
+    async fn tags(&self, image: &ImageRef) -> Result<Vec<TagInfo>, String> {
+        let backend = &self.backend;
+        backend
+            .load_tags(image, request_oci_tags(backend, image))
+            .await
+    }
+
+    async fn digest(&self, image: &ImageRef, tag: &str) -> Result<String, String> {
+        self.backend.load_digest(image, tag).await
+    }
+
+    async fn platforms(&self, image: &ImageRef, tag: &str) -> Result<Vec<Platform>, String> {
+        self.backend.load_platforms(image, tag).await
+    }
+
+    fn concurrency(&self) -> usize {
+        self.backend.options.concurrency
+    }
+}
+
+/// Fixed tags per repository, e.g. for tests or tags that are known in advance.
+#[derive(Debug, Default)]
+pub struct StaticTagSource {
+    tags: HashMap<String, Vec<TagInfo>>,
+}
+
+impl StaticTagSource {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the tags for an image name like `python` or `ghcr.io/owner/image`.
+    pub fn with_tags(mut self, image: &str, tags: Vec<TagInfo>) -> Self {
+        self.tags
+            .insert(ImageRef::parse(image).repository_key(), tags);
+        self
+    }
+}
+
+#[async_trait]
+impl TagSource for StaticTagSource {
+    async fn tags(&self, image: &ImageRef) -> Result<Vec<TagInfo>, String> {
+        self.tags
+            .get(&image.repository_key())
+            .cloned()
+            .ok_or_else(|| format!("no tags known for {}", image))
+    }
+}
+
+/// What the registry sources share: the http client, the cache and the settings.
+#[derive(Debug)]
+struct RegistryBackend {
+    client: RegistryClient,
+    cache: Option<TagCache>,
+    options: RegistryOptions,
+    /// Platforms per repository and tag that were looked up in a manifest, so each tag is requested once per run.
+    platforms: Mutex<HashMap<(String, String), Vec<Platform>>>,
+}
+
+impl RegistryBackend {
+    fn new(options: &RegistryOptions) -> Self {
+        RegistryBackend {
+            client: RegistryClient::new(options),
+            cache: options.cache_dir.as_deref().map(TagCache::new),
+            options: options.clone(),
+            platforms: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Get the tags of an image from the cache if it is fresh enough (or offline), from the registry otherwise.
+    async fn load_tags(
+        &self,
+        image: &ImageRef,
+        request: impl Future<Output = Result<Vec<TagInfo>, String>>,
+    ) -> Result<Vec<TagInfo>, String> {
+        let key = image.repository_key();
+        let cached = match &self.cache {
+            Some(cache) => cache.load(&key).await,
+            None => None,
+        };
+        if self.options.offline {
+            return cached.map(|cached| cached.tags).ok_or_else(|| {
+                format!(
+                    "no cached tags for {}, cannot look them up in offline mode",
+                    &key
+                )
+            });
+        }
+        if let Some(cached) = cached {
+            if cached.is_fresh(self.options.cache_ttl, Utc::now()) {
+                debug!("using cached tags for {}", &key);
+                return Ok(cached.tags);
+            }
+        }
+        let tags = request.await?;
+        if let Some(cache) = &self.cache {
+            if let Err(err) = cache.store(&key, &tags).await {
+                warn!("{}", err);
+            }
+        }
+        Ok(tags)
+    }
+
+    /// Get the digest of a tag from the registry, or from the cached listing in offline mode.
+    async fn load_digest(&self, image: &ImageRef, tag: &str) -> Result<String, String> {
+        if self.options.offline {
+            let cached = match &self.cache {
+                Some(cache) => cache.load(&image.repository_key()).await,
+                None => None,
+            };
+            return cached
+                .and_then(|cached| cached.tags.into_iter().find(|info| info.name() == tag))
+                .and_then(|info| info.digest().clone())
+                .ok_or_else(|| {
+                    format!(
+                        "no cached digest for {}:{}, cannot look it up in offline mode",
+                        image, tag
+                    )
+                });
+        }
+        let url = format!(
+            "{}/v2/{}/manifests/{}",
+            registry_base_url(image),
+            image.path(),
+            tag
+        );
+        request_digest(&self.client, &url, &image.path()).await
+    }
+
+    /// Get the platforms of a tag from the manifest in the registry, or from the cached listing in offline mode.
+    async fn load_platforms(&self, image: &ImageRef, tag: &str) -> Result<Vec<Platform>, String> {
+        if self.options.offline {
+            let cached = match &self.cache {
+                Some(cache) => cache.load(&image.repository_key()).await,
+                None => None,
+            };
+            return Ok(cached
+                .and_then(|cached| cached.tags.into_iter().find(|info| info.name() == tag))
+                .map(|info| info.platforms().clone())
+                .unwrap_or_default());
+        }
+        let key = (image.repository_key(), tag.to_owned());
+        if let Some(platforms) = self.platforms.lock().unwrap().get(&key) {
+            return Ok(platforms.clone());
+        }
+        let platforms = request_platforms(&self.client, image, tag).await?;
+        self.platforms
+            .lock()
+            .unwrap()
+            .insert(key, platforms.clone());
+        Ok(platforms)
+    }
+
+    fn is_last_page(&self, page: usize) -> bool {
+        is_last_page(page, &self.options)
+    }
+}
+
+/// Base url of the OCI Distribution api of the registry that hosts the image.
+fn registry_base_url(image: &ImageRef) -> String {
+    match image.registry() {
+        Registry::DockerHub => DOCKER_HUB_REGISTRY_URL.to_owned(),
+        Registry::Oci { host } => Registry::oci_base_url(host),
+    }
+}
+
+fn url_for_parent(image: &ImageRef) -> String {
+    format!(
+        "https://hub.docker.com/v2/namespaces/{}/repositories/{}/tags?page_size=1000",
+        image.namespace(),
+        image.repository()
+    )
+}
+
+/// Request json from a registry, returning the parsed body and the next page from the `Link` header.
+/// If a previous response for the url is cached with an `ETag` or `Last-Modified`, the request is
+/// conditional, and the cached response is used if the registry answers `304 Not Modified`.
+async fn request_json<T: DeserializeOwned>(
+    client: &RegistryClient,
+    cache: Option<&TagCache>,
+    url: &str,
+    repository: &str,
+) -> Result<(T, Option<String>), String> {
+    let cached = match cache {
+        Some(cache) => cache.load_response(url).await,
+        None => None,
+    };
+    let mut headers = HeaderMap::new();
+    if let Some(cached) = &cached {
+        if let Some(etag) = cached.etag.as_ref().and_then(|etag| etag.parse().ok()) {
+            headers.insert(IF_NONE_MATCH, etag);
+        }
+        if let Some(modified) = cached
+            .last_modified
+            .as_ref()
+            .and_then(|modified| modified.parse().ok())
+        {
+            headers.insert(IF_MODIFIED_SINCE, modified);
+        }
+    }
+    let resp = client
+        .get(url, repository, &headers)
+        .await
+        .map_err(|err| format!("Failed to request available image tags: {}", err))?;
+    let status = resp.status();
+    if let (StatusCode::NOT_MODIFIED, Some(cached)) = (status, &cached) {
+        debug!("tags not modified since previous request: {}", url);
+        let value = parse_tags_json(&cached.body, url, repository)?;
+        return Ok((value, cached.next_url.clone()));
+    }
+    let header_str = |name| {
+        resp.headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.to_owned())
+    };
+    let next_url = header_str(LINK)
+        .and_then(|link| LINK_NEXT_RE.captures(&link).map(|next| next[1].to_owned()));
+    let etag = header_str(ETAG);
+    let last_modified = header_str(LAST_MODIFIED);
+    let data = resp.text().await.map_err(|err| {
+        format!(
+            "Failed to request available image tags: err {} for {}",
+            err, &url
+        )
+    })?;
+    if !status.is_success() {
+        return Err(format!(
+            "Failed to request available image tags for {}: {}",
+            repository,
+            describe_error(status.as_u16(), &data)
+        ));
+    }
+    let value = parse_tags_json(&data, url, repository)?;
+    if let (Some(cache), true) = (cache, etag.is_some() || last_modified.is_some()) {
+        let response = CachedResponse {
+            url: url.to_owned(),
+            etag,
+            last_modified,
+            next_url: next_url.clone(),
+            body: data,
+        };
+        if let Err(err) = cache.store_response(&response).await {
+            warn!("{}", err);
+        }
+    }
+    Ok((value, next_url))
+}
+
+fn parse_tags_json<T: DeserializeOwned>(
+    data: &str,
+    url: &str,
+    repository: &str,
+) -> Result<T, String> {
+    serde_json::from_str::<T>(data).map_err(|err| {
+        format!(
+            "Unexpected response when requesting image tags for {} from {}: {}",
+            repository, url, err
+        )
+    })
+}
+
+/// List tags through the Docker Hub api, following `next` links to get all pages.
+async fn request_docker_hub_tags(
+    backend: &RegistryBackend,
+    image: &ImageRef,
+) -> Result<Vec<TagInfo>, String> {
+    let mut url = url_for_parent(image);
+    let mut tags = vec![];
+    for page in 1.. {
+        let (data, _) = request_json::<DockerHubTagPage>(
+            &backend.client,
+            backend.cache.as_ref(),
+            &url,
+            &image.path(),
+        )
+        .await?;
+        tags.extend(data.results.into_iter().map(TagInfo::from));
+        match data.next {
+            Some(_) if backend.is_last_page(page) => {
+                info!("stopping after {} pages of tags for {}", page, image);
+                break;
+            }
+            Some(next_url) => url = next_url,
+            None => break,
+        }
+    }
+    Ok(tags)
+}
+
+fn is_last_page(page: usize, options: &RegistryOptions) -> bool {
+    options
+        .max_tag_pages
+        .map(|max_pages| page >= max_pages)
+        .unwrap_or(false)
+}
+
+/// List tags through the OCI Distribution api, following `Link` headers to get all pages.
+async fn request_oci_tags(
+    backend: &RegistryBackend,
+    image: &ImageRef,
+) -> Result<Vec<TagInfo>, String> {
+    let base_url = registry_base_url(image);
+    let repository = image.path();
+    let mut url = format!("{}/v2/{}/tags/list", &base_url, &repository);
+    let mut tags = vec![];
+    for page in 1.. {
+        let (data, next_url) =
+            request_json::<OciTagList>(&backend.client, backend.cache.as_ref(), &url, &repository)
+                .await?;
+        tags.extend(data.into_tags());
+        match next_url {
+            Some(_) if backend.is_last_page(page) => {
+                info!("stopping after {} pages of tags for {}", page, image);
+                break;
+            }
+            Some(next_url) if next_url.starts_with('/') => {
+                url = format!("{}{}", &base_url, next_url)
+            }
+            Some(next_url) => url = next_url,
+            None => break,
+        }
+    }
+    Ok(tags)
+}
+
+/// Request the digest of a manifest (or index for multi-platform images) through the OCI Distribution api.
+async fn request_digest(
+    client: &RegistryClient,
+    url: &str,
+    repository: &str,
+) -> Result<String, String> {
+    let mut manifest_accept = HeaderMap::new();
+    manifest_accept.insert(ACCEPT, HeaderValue::from_static(MANIFEST_ACCEPT));
+    let resp = client
+        .head(url, repository, &manifest_accept)
+        .await
+        .map_err(|err| format!("Failed to request Docker image digest: {}", err))?;
+    if !resp.status().is_success() {
+        return Err(format!(
+            "Failed to request Docker image digest: status {} for {}",
+            resp.status(),
+            url
+        ));
+    }
+    resp.headers()
+        .get("docker-content-digest")
+        .and_then(|digest| digest.to_str().ok())
+        .map(|digest| digest.to_owned())
+        .ok_or_else(|| format!("No digest in registry response for {}", url))
+}
+
+/// Request the platforms of a tag through the OCI Distribution api. For an index these are listed in the
+/// manifest, for single-platform images they are in the config blob.
+async fn request_platforms(
+    client: &RegistryClient,
+    image: &ImageRef,
+    tag: &str,
+) -> Result<Vec<Platform>, String> {
+    let base_url = registry_base_url(image);
+    let repository = image.path();
+    let mut manifest_accept = HeaderMap::new();
+    manifest_accept.insert(ACCEPT, HeaderValue::from_static(MANIFEST_ACCEPT));
+    let url = format!("{}/v2/{}/manifests/{}", &base_url, &repository, tag);
+    let manifest =
+        request_manifest_json::<OciManifest>(client, &url, &repository, &manifest_accept).await?;
+    if !manifest.manifests.is_empty() {
+        return Ok(manifest.index_platforms());
+    }
+    let Some(config) = manifest.config else {
+        return Ok(vec![]);
+    };
+    let url = format!("{}/v2/{}/blobs/{}", &base_url, &repository, &config.digest);
+    let platform =
+        request_manifest_json::<OciPlatform>(client, &url, &repository, &HeaderMap::new()).await?;
+    Ok(vec![Platform::from(platform)])
+}
+
+async fn request_manifest_json<T: DeserializeOwned>(
+    client: &RegistryClient,
+    url: &str,
+    repository: &str,
+    headers: &HeaderMap,
+) -> Result<T, String> {
+    let resp = client
+        .get(url, repository, headers)
+        .await
+        .map_err(|err| format!("Failed to request Docker image manifest: {}", err))?;
+    let status = resp.status();
+    let data = resp.text().await.map_err(|err| {
+        format!(
+            "Failed to request Docker image manifest: err {} for {}",
+            err, url
+        )
+    })?;
+    if !status.is_success() {
+        return Err(format!(
+            "Failed to request Docker image manifest for {}: {}",
+            repository,
+            describe_error(status.as_u16(), &data)
+        ));
+    }
+    serde_json::from_str::<T>(&data).map_err(|err| {
+        format!(
+            "Unexpected response when requesting image manifest for {} from {}: {}",
+            repository, url, err
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn docker_hub_url() {
+        assert_eq!(
+            url_for_parent(&ImageRef::parse("python")),
+            "https://hub.docker.com/v2/namespaces/library/repositories/python/tags?page_size=1000"
+        );
+        assert_eq!(
+            url_for_parent(&ImageRef::parse("docker.io/mverleg/rust_nightly_musl_base")),
+            "https://hub.docker.com/v2/namespaces/mverleg/repositories/rust_nightly_musl_base/tags?page_size=1000"
+        );
+    }
+
+    #[test]
+    fn page_cap() {
+        let unlimited = RegistryOptions::default();
+        assert!(!is_last_page(1, &unlimited));
+        assert!(!is_last_page(1000, &unlimited));
+        let capped = RegistryOptions {
+            max_tag_pages: Some(3),
+            ..RegistryOptions::default()
+        };
+        assert!(!is_last_page(2, &capped));
+        assert!(is_last_page(3, &capped));
+    }
+
+    #[test]
+    fn registry_shares_backend() {
+        let source = RegistryTagSource::new(&RegistryOptions {
+            concurrency: 3,
+            ..RegistryOptions::default()
+        });
+        assert!(Arc::ptr_eq(&source.docker_hub.backend, &source.oci.backend));
+        assert_eq!(source.concurrency(), 3);
+    }
+
+    #[tokio::test]
+    async fn reuse_platforms() {
+        let backend = RegistryBackend::new(&RegistryOptions::default());
+        let image = ImageRef::parse("ghcr.io/owner/image");
+        let platforms = vec![Platform::parse("linux/arm64").unwrap()];
+        backend.platforms.lock().unwrap().insert(
+            (image.repository_key(), "1.2".to_owned()),
+            platforms.clone(),
+        );
+        assert_eq!(backend.load_platforms(&image, "1.2").await, Ok(platforms));
+    }
+
+    #[tokio::test]
+    async fn static_tags_and_digest() {
+        let source = StaticTagSource::new().with_tags(
+            "docker.io/library/python",
+            vec![
+                TagInfo::new("3.11".to_owned()),
+                TagInfo::new("3.12".to_owned()).with_metadata(
+                    None,
+                    Some("sha256:abc".to_owned()),
+                    vec![],
+                ),
+            ],
+        );
+        let python = ImageRef::parse("python");
+        assert_eq!(source.tags(&python).await.unwrap().len(), 2);
+        assert_eq!(
+            source.digest(&python, "3.12").await,
+            Ok("sha256:abc".to_owned())
+        );
+        assert!(source.digest(&python, "3.11").await.is_err());
+        assert!(source.tags(&ImageRef::parse("node")).await.is_err());
+    }
+}
@@ -1,32 +1,64 @@
+use ::std::collections::HashMap;
 use ::std::collections::HashSet;
+use ::std::ops::Range;
+use ::std::path::PathBuf;
 
+use ::chrono::Utc;
 use ::futures::{stream, StreamExt, TryStreamExt};
 use ::indexmap::IndexMap;
 use ::itertools::Itertools;
-use ::lazy_static::lazy_static;
-use ::log::debug;
-use ::regex::Regex;
-use ::reqwest::Client;
+use ::log::{info, warn};
 
 use crate::dvb::convert::parse_tag;
-use crate::dvb::data::Tag;
+use crate::dvb::data::{HeldBack, Tag, TagInfo};
+use crate::dvb::policy::{missing_platforms, BumpPolicy};
+use crate::dvb::registry::ImageRef;
+use crate::dvb::source::TagSource;
 use crate::Parent;
 
-lazy_static! {
-    static ref NAME_TAG_RE: Regex = Regex::new("\"name\":\\s*\"([^\"]*)\"").unwrap();
+/// A global `ARG` used in the tag of several FROM lines, identified by its file and the position of its default.
+pub type SharedArg = (PathBuf, Range<usize>);
+
+/// The `ARG`s used by parents that are not bumped, with their current tag, so that the other FROM lines that
+/// use the same `ARG` keep that value too.
+pub fn pinned_args(skipped: &HashSet<Parent>) -> HashMap<SharedArg, Tag> {
+    skipped
+        .iter()
+        .filter_map(|parent| Some((shared_arg(parent)?, parent)))
+        .inspect(|(_, parent)| {
+            info!(
+                "not bumping ARG {} because it is also used by skipped {}",
+                parent.tag_arg().as_ref().unwrap().name(),
+                parent.image_name()
+            )
+        })
+        .map(|(shared, parent)| (shared, parent.tag().clone()))
+        .collect()
 }
 
+/// Find the latest tags for the parents. Those that use a pinned `ARG` are not bumped past its current value.
 pub async fn find_latest_tag(
     parents: HashSet<Parent>,
-    bump_major: bool,
+    pinned: &HashMap<SharedArg, Tag>,
+    policy: &BumpPolicy,
+    source: &dyn TagSource,
 ) -> Result<IndexMap<Parent, Tag>, String> {
-    let client = Client::new();
-
-    let latest_tags = stream::iter(parents.into_iter().map(url_for_parent))
-        .map(|(url, parent)| load_filter_tags(parent, &client, url, bump_major))
-        .buffer_unordered(8)
-        .try_collect::<Vec<_>>()
-        .await?;
+    let mut latest_tags = load_all_tags(parents, policy, source, pinned).await?;
+    // FROM lines that share an `ARG` get one value, so pick again with the lowest choice as the ceiling
+    // until they agree. The ceiling goes down every round, so this ends at the latest at the current value.
+    loop {
+        let ceilings = shared_arg_ceilings(&latest_tags);
+        if ceilings.is_empty() {
+            break;
+        }
+        let (again, mut agreed): (Vec<_>, Vec<_>) =
+            latest_tags.into_iter().partition(|(parent, _)| {
+                shared_arg(parent).is_some_and(|shared| ceilings.contains_key(&shared))
+            });
+        let again = again.into_iter().map(|(parent, _)| parent).collect();
+        agreed.extend(load_all_tags(again, policy, source, &ceilings).await?);
+        latest_tags = agreed;
+    }
 
     Ok(latest_tags
         .into_iter()
@@ -39,62 +71,218 @@ pub async fn find_latest_tag(
         .collect::<IndexMap<Parent, Tag>>())
 }
 
-fn url_for_parent(parent: Parent) -> (String, Parent) {
-    (
-        format!(
-            "https://hub.docker.com/v2/namespaces/library/repositories/{}/tags?page_size=1000",
-            &parent.image_name()
-        ),
-        parent,
-    )
+async fn load_all_tags(
+    parents: HashSet<Parent>,
+    policy: &BumpPolicy,
+    source: &dyn TagSource,
+    ceilings: &HashMap<SharedArg, Tag>,
+) -> Result<Vec<(Parent, Tag)>, String> {
+    // Parents from the same repository share one tag listing.
+    let parent_groups = parents
+        .into_iter()
+        .into_group_map_by(|parent| ImageRef::parse(parent.image_name()).repository_key());
+    let latest_tags = stream::iter(parent_groups.into_values())
+        .map(|parents| load_filter_tags(parents, source, policy, ceilings))
+        .buffer_unordered(source.concurrency().max(1))
+        .try_collect::<Vec<_>>()
+        .await?;
+    Ok(latest_tags.into_iter().flatten().collect())
 }
 
-async fn load_filter_tags(
-    parent: Parent,
-    client: &Client,
-    url: String,
-    bump_major: bool,
-) -> Result<(Parent, Tag), String> {
-    let data = request_tag_json(client, &url).await?;
-    let tag = find_highest(&parent, &data, bump_major)?;
-    Ok((parent, tag))
+fn shared_arg(parent: &Parent) -> Option<SharedArg> {
+    parent.tag_arg().as_ref()?;
+    let span = parent.tag_span().clone()?;
+    Some((parent.dockerfile().path().to_owned(), span))
 }
 
-fn find_highest(parent: &Parent, data: &str, bump_major: bool) -> Result<Tag, String> {
-    let tag = NAME_TAG_RE
-        .captures_iter(data)
-        .filter(|tag| parent.tag_pattern().is_match(&tag[1]))
-        .map(|tag| parse_tag(parent.tag_pattern(), &tag[1]).unwrap())
-        .filter(|tag| tag >= parent.tag())
-        .filter(|tag| bump_major || tag.major() == parent.tag().major())
-        .sorted()
-        .rev()
-        .next()
-        .ok_or_else(|| {
-            format!(
-                "could not find the version {} nor any higher ones for {}",
-                parent.tag(),
-                &parent.image_name(),
-            )
-        })?;
-    Ok(tag)
+/// The lowest chosen tag for each shared `ARG` for which the FROM lines chose different values.
+fn shared_arg_ceilings(latest_tags: &[(Parent, Tag)]) -> HashMap<SharedArg, Tag> {
+    latest_tags
+        .iter()
+        .filter_map(|(parent, tag)| {
+            let value = parent.tag_arg().as_ref()?.value_for(tag.name());
+            Some((shared_arg(parent)?, (value, tag)))
+        })
+        .into_group_map()
+        .into_iter()
+        .filter(|(_, choices)| !choices.iter().map(|(value, _)| value).all_equal())
+        .map(|(shared, choices)| {
+            let lowest = choices.into_iter().map(|(_, tag)| tag).min().unwrap();
+            (shared, lowest.clone())
+        })
+        .collect()
 }
 
-async fn request_tag_json(client: &Client, url: &str) -> Result<String, String> {
-    debug!("request to: {}", &url);
-    let resp = client.get(url).send().await.map_err(|err| {
-        format!(
-            "Failed to request available Docker image tags: err {} for {}",
-            err, &url
-        )
-    })?;
-    let data = resp.text().await.map_err(|err| {
-        format!(
-            "Failed to request available Docker image tags: err {} for {}",
-            err, &url
-        )
-    })?;
-    Ok(data)
+/// Find the latest tag for each of the parents, which must all be from the same repository.
+async fn load_filter_tags(
+    parents: Vec<Parent>,
+    source: &dyn TagSource,
+    policy: &BumpPolicy,
+    ceilings: &HashMap<SharedArg, Tag>,
+) -> Result<Vec<(Parent, Tag)>, String> {
+    let Some(first) = parents.first() else {
+        return Ok(vec![]);
+    };
+    let image = ImageRef::parse(first.image_name());
+    let tags = source.tags(&image).await?;
+    let mut digests: HashMap<String, String> = HashMap::new();
+    let mut latest_tags = Vec::with_capacity(parents.len());
+    for parent in parents {
+        let ceiling = shared_arg(&parent).and_then(|shared| ceilings.get(&shared));
+        let mut tag = find_highest(&parent, &tags, &image, source, policy, ceiling).await?;
+        if let Some(old_digest) = parent.digest() {
+            let digest = match digests.get(tag.name()) {
+                Some(digest) => Ok(digest.clone()),
+                None => source.digest(&image, tag.name()).await,
+            };
+            tag = match digest {
+                Ok(digest) => {
+                    digests.insert(tag.name().to_owned(), digest.clone());
+                    tag.with_digest(digest)
+                }
+                Err(err) => {
+                    // Keep the line as it is, rather than pinning a new tag to the old digest.
+                    warn!(
+                        "warning: not updating {} because the digest of {} could not be found: {}",
+                        parent.image_name(),
+                        tag.name(),
+                        err
+                    );
+                    let mut held_back = tag.held_back().clone();
+                    if tag.name() != parent.tag().name() {
+                        let reason = format!("without digest: {}", err);
+                        held_back.push(HeldBack::new(tag.name().to_owned(), reason));
+                    }
+                    parent
+                        .tag()
+                        .clone()
+                        .with_digest(old_digest.clone())
+                        .with_held_back(held_back)
+                }
+            };
+        }
+        latest_tags.push((parent, tag));
+    }
+    Ok(latest_tags)
+}
+
+/// Find the highest tag that matches the parent and is allowed by the policy. Tags that do not satisfy the
+/// constraint, are too new, or are not available for all required platforms, are skipped and reported as held back.
+/// The current tag is always allowed. Tags above the ceiling are skipped too, it is set when other FROM lines
+/// that share the `ARG` of this tag cannot use a higher value.
+async fn find_highest(
+    parent: &Parent,
+    tags: &[TagInfo],
+    image: &ImageRef,
+    source: &dyn TagSource,
+    policy: &BumpPolicy,
+    ceiling: Option<&Tag>,
+) -> Result<Tag, String> {
+    let platforms = policy.platforms_for(parent);
+    let level = policy.level_for(parent);
+    let constraint = policy.constraint_for(parent);
+    let now = Utc::now();
+    let candidates = tags
+        .iter()
+        .filter(|info| parent.tag_pattern().is_match(info.name()))
+        .map(|info| (parse_tag(parent.tag_pattern(), info.name()).unwrap(), info))
+        .filter(|(tag, _)| tag >= parent.tag())
+        .filter(|(tag, _)| level.allows(parent.tag(), tag))
+        .filter(|(tag, _)| policy.allows_pre_release(parent.tag(), tag))
+        .sorted_by(|(tag1, _), (tag2, _)| tag1.cmp(tag2))
+        .rev();
+    let mut held_back = vec![];
+    let mut is_constraint_reported = false;
+    let mut is_ceiling_reported = false;
+    for (tag, info) in candidates {
+        if tag.name() == parent.tag().name() {
+            return Ok(tag.with_held_back(held_back));
+        }
+        if let (Some(ceiling), Some(tag_arg)) = (ceiling, parent.tag_arg()) {
+            if &tag > ceiling {
+                // Only report the newest skipped tag, like for constraints.
+                if !is_ceiling_reported {
+                    let reason = format!(
+                        "limited by the other FROM lines that use ARG {}",
+                        tag_arg.name()
+                    );
+                    info!(
+                        "skipping {}:{} because it is {}",
+                        parent.image_name(),
+                        tag.name(),
+                        &reason
+                    );
+                    held_back.push(HeldBack::new(tag.name().to_owned(), reason));
+                    is_ceiling_reported = true;
+                }
+                continue;
+            }
+        }
+        if let Some(constraint) = constraint.filter(|constraint| !constraint.matches(&tag)) {
+            // Only report the newest excluded tag, there may be many.
+            if !is_constraint_reported {
+                let reason = format!("excluded by constraint {}", constraint);
+                info!(
+                    "skipping {}:{} because it is {}",
+                    parent.image_name(),
+                    tag.name(),
+                    &reason
+                );
+                held_back.push(HeldBack::new(tag.name().to_owned(), reason));
+                is_constraint_reported = true;
+            }
+            continue;
+        }
+        if let Some(reason) = policy.too_new(info, now) {
+            info!(
+                "skipping {}:{} because it was {}",
+                parent.image_name(),
+                tag.name(),
+                &reason
+            );
+            held_back.push(HeldBack::new(tag.name().to_owned(), reason));
+            continue;
+        }
+        if !platforms.is_empty() {
+            let available = if info.platforms().is_empty() {
+                // Costs a manifest request, so this is done last and stops at the first tag that is available.
+                match source.platforms(image, info.name()).await {
+                    Ok(available) => available,
+                    Err(err) => {
+                        let reason = format!("platforms unknown: {}", err);
+                        warn!(
+                            "warning: skipping {}:{} because its platforms could not be found: {}",
+                            parent.image_name(),
+                            tag.name(),
+                            err
+                        );
+                        held_back.push(HeldBack::new(tag.name().to_owned(), reason));
+                        continue;
+                    }
+                }
+            } else {
+                info.platforms().clone()
+            };
+            let missing = missing_platforms(&available, &platforms);
+            if !missing.is_empty() {
+                let reason = format!("not available for {}", missing.iter().join(", "));
+                info!(
+                    "skipping {}:{} because it is {}",
+                    parent.image_name(),
+                    tag.name(),
+                    &reason
+                );
+                held_back.push(HeldBack::new(tag.name().to_owned(), reason));
+                continue;
+            }
+        }
+        return Ok(tag.with_held_back(held_back));
+    }
+    Err(format!(
+        "could not find the version {} nor any higher ones for {}",
+        parent.tag(),
+        &parent.image_name(),
+    ))
 }
 
 #[cfg(test)]
@@ -102,49 +290,405 @@ mod tests {
     use ::std::path::PathBuf;
     use ::std::rc::Rc;
 
-    use crate::dvb::data::Dockerfile;
+    use ::regex::Regex;
+
+    use crate::dvb::api::DockerHubTagPage;
+    use crate::dvb::convert::tag_to_re;
+    use crate::dvb::data::{Dockerfile, Platform};
+    use crate::dvb::policy::BumpLevel;
+    use crate::dvb::read::extract_parents;
+    use crate::dvb::source::StaticTagSource;
 
     use super::*;
 
-    static TAGS_JSON: &str = "[\
+    static TAGS_JSON: &str = "{\"count\": 6, \"next\": null, \"results\": [\
             {\"layer\": \"\", \"name\": \"2.5.1-full\"}, \
             {\"layer\": \"\", \"name\": \"3.6.6-full\"}, \
-            {\"layer\": \"\", \"name\": \"3.6.6-alpine-perl\"}, \
+            {\"layer\": \"\", \"name\": \"3.6.6-alpine-perl\", \"images\": [{\"name\": \"9.9.9-alpine\"}]}, \
             {\"layer\": \"\", \"name\": \"2.4.1-alpine\"}, \
             {\"layer\": \"\", \"name\": \"3.5.2-alpine\"}, \
-            {\"layer\": \"\", \"name\": \"1.9.9-alpine\"}]";
+            {\"layer\": \"\", \"name\": \"1.9.9-alpine\"}]}";
+
+    fn docker_hub_tags(data: &str) -> Vec<TagInfo> {
+        serde_json::from_str::<DockerHubTagPage>(data)
+            .unwrap()
+            .results
+            .into_iter()
+            .map(TagInfo::from)
+            .collect()
+    }
 
-    #[test]
-    fn bump_minor() {
+    fn alpine_parent() -> Parent {
         let dockerfile = Rc::new(Dockerfile::new(PathBuf::from("file.ext"), "".to_owned()));
-        let parent = Parent::new(
+        Parent::new(
             dockerfile,
-            "".to_owned(),
+            "alpine".to_owned(),
             Regex::new(r"^([0-9]+)\.([0-9]+)\.([0-9]+)\-alpine$").unwrap(),
             Tag::new("2.2.8-alpine".to_owned(), (2, 2, 8, 0)),
             "AS build".to_owned(),
-        );
-        let highest = find_highest(&parent, TAGS_JSON, false);
+        )
+    }
+
+    async fn highest(
+        parent: &Parent,
+        tags: &[TagInfo],
+        source: &dyn TagSource,
+        policy: &BumpPolicy,
+    ) -> Result<Tag, String> {
+        find_highest(
+            parent,
+            tags,
+            &ImageRef::parse("alpine"),
+            source,
+            policy,
+            None,
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn bump_minor() {
+        let highest = highest(
+            &alpine_parent(),
+            &docker_hub_tags(TAGS_JSON),
+            &StaticTagSource::new(),
+            &BumpPolicy::default(),
+        )
+        .await;
         assert_eq!(
             highest,
             Ok(Tag::new("2.4.1-alpine".to_owned(), (2, 4, 1, 0)))
         );
     }
 
-    #[test]
-    fn bump_major() {
+    #[tokio::test]
+    async fn bump_major() {
+        let policy = BumpPolicy {
+            level: BumpLevel::Major,
+            ..BumpPolicy::default()
+        };
+        let highest = highest(
+            &alpine_parent(),
+            &docker_hub_tags(TAGS_JSON),
+            &StaticTagSource::new(),
+            &policy,
+        )
+        .await;
+        assert_eq!(
+            highest,
+            Ok(Tag::new("3.5.2-alpine".to_owned(), (3, 5, 2, 0)))
+        );
+    }
+
+    #[tokio::test]
+    async fn skip_missing_platform() {
+        let amd64 = Platform::parse("linux/amd64").unwrap();
+        let arm64 = Platform::parse("linux/arm64/v8").unwrap();
+        let tag = |name: &str, platforms: &[&Platform]| {
+            TagInfo::new(name.to_owned()).with_metadata(
+                None,
+                None,
+                platforms
+                    .iter()
+                    .map(|platform| (*platform).clone())
+                    .collect(),
+            )
+        };
+        let listed = vec![
+            tag("2.2.8-alpine", &[&amd64]),
+            tag("2.3.0-alpine", &[&amd64, &arm64]),
+            tag("2.3.1-alpine", &[]),
+            tag("2.4.0-alpine", &[&amd64]),
+        ];
+        let source = StaticTagSource::new()
+            .with_tags("alpine", vec![tag("2.3.1-alpine", &[&amd64, &arm64])]);
+        let policy = BumpPolicy {
+            platforms: vec![Platform::parse("linux/arm64").unwrap()],
+            ..BumpPolicy::default()
+        };
+        let selected = highest(&alpine_parent(), &listed, &source, &policy)
+            .await
+            .unwrap();
+        assert_eq!(selected, Tag::new("2.3.1-alpine".to_owned(), (2, 3, 1, 0)));
+        assert_eq!(
+            selected.held_back(),
+            &vec![HeldBack::new(
+                "2.4.0-alpine".to_owned(),
+                "not available for linux/arm64".to_owned()
+            )]
+        );
+        let policy = BumpPolicy {
+            platforms: vec![Platform::parse("linux/s390x").unwrap()],
+            ..BumpPolicy::default()
+        };
+        let selected = highest(&alpine_parent(), &listed, &source, &policy).await;
+        assert_eq!(
+            selected,
+            Ok(Tag::new("2.2.8-alpine".to_owned(), (2, 2, 8, 0)))
+        );
+    }
+
+    #[tokio::test]
+    async fn skip_failed_platform_lookup() {
+        let listed = ["2.2.8-alpine", "2.3.0-alpine", "2.3.1-alpine"]
+            .iter()
+            .map(|name| TagInfo::new(name.to_string()))
+            .collect::<Vec<_>>();
+        let policy = BumpPolicy {
+            platforms: vec![Platform::parse("linux/arm64").unwrap()],
+            ..BumpPolicy::default()
+        };
+        // The source knows no tags, so every platform lookup fails.
+        let selected = highest(&alpine_parent(), &listed, &StaticTagSource::new(), &policy)
+            .await
+            .unwrap();
+        assert_eq!(selected.name(), "2.2.8-alpine");
+        let reasons = selected
+            .held_back()
+            .iter()
+            .map(|held_back| held_back.reason().as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(reasons.len(), 2);
+        assert!(reasons
+            .iter()
+            .all(|reason| reason.starts_with("platforms unknown: no tags known for")));
+    }
+
+    #[tokio::test]
+    async fn hold_back_recent() {
+        let pushed = |name: &str, days_ago: i64| {
+            TagInfo::new(name.to_owned()).with_metadata(
+                Some(Utc::now() - chrono::Duration::days(days_ago)),
+                None,
+                vec![],
+            )
+        };
+        let listed = vec![
+            pushed("2.2.8-alpine", 100),
+            pushed("2.3.0-alpine", 10),
+            pushed("2.3.1-alpine", 1),
+            TagInfo::new("2.2.9-alpine".to_owned()),
+        ];
+        let policy = BumpPolicy {
+            min_age: std::time::Duration::from_secs(7 * 86400),
+            ..BumpPolicy::default()
+        };
+        let selected = highest(&alpine_parent(), &listed, &StaticTagSource::new(), &policy)
+            .await
+            .unwrap();
+        assert_eq!(selected, Tag::new("2.3.0-alpine".to_owned(), (2, 3, 0, 0)));
+        assert_eq!(selected.held_back().len(), 1);
+        assert_eq!(selected.held_back()[0].tag(), "2.3.1-alpine");
+    }
+
+    #[tokio::test]
+    async fn skip_pre_release() {
         let dockerfile = Rc::new(Dockerfile::new(PathBuf::from("file.ext"), "".to_owned()));
+        let parent = |tag: &str| {
+            let tag_pattern = tag_to_re(tag).unwrap();
+            let tag = parse_tag(&tag_pattern, tag).unwrap();
+            Parent::new(
+                dockerfile.clone(),
+                "python".to_owned(),
+                tag_pattern,
+                tag,
+                "".to_owned(),
+            )
+        };
+        let listed = ["3.12.7", "3.13.0rc2", "3.13.0rc3", "3.13.1rc1", "3.14.0a1"]
+            .iter()
+            .map(|name| TagInfo::new(name.to_string()))
+            .collect::<Vec<_>>();
+        let source = StaticTagSource::new();
+        let chosen = |parent: Parent, policy: BumpPolicy| {
+            let listed = listed.clone();
+            let source = &source;
+            async move {
+                highest(&parent, &listed, source, &policy)
+                    .await
+                    .unwrap()
+                    .name()
+                    .to_owned()
+            }
+        };
+        assert_eq!(
+            chosen(parent("3.12.1"), BumpPolicy::default()).await,
+            "3.12.7"
+        );
+        assert_eq!(
+            chosen(parent("3.13.0rc2"), BumpPolicy::default()).await,
+            "3.13.0rc3"
+        );
+        let policy = BumpPolicy {
+            pre_release: true,
+            ..BumpPolicy::default()
+        };
+        assert_eq!(chosen(parent("3.12.1"), policy).await, "3.14.0a1");
+    }
+
+    #[tokio::test]
+    async fn keep_dev_flavour() {
+        let dockerfile = Rc::new(Dockerfile::new(PathBuf::from("file.ext"), "".to_owned()));
+        let tag_pattern = tag_to_re("8.2-dev").unwrap();
+        let tag = parse_tag(&tag_pattern, "8.2-dev").unwrap();
         let parent = Parent::new(
             dockerfile,
+            "php".to_owned(),
+            tag_pattern,
+            tag,
             "".to_owned(),
-            Regex::new(r"^([0-9]+)\.([0-9]+)\.([0-9]+)\-alpine$").unwrap(),
-            Tag::new("2.2.8-alpine".to_owned(), (2, 2, 8, 0)),
-            "AS build".to_owned(),
         );
-        let highest = find_highest(&parent, TAGS_JSON, true);
+        let listed = ["8.2-dev", "8.3-dev", "8.4"]
+            .iter()
+            .map(|name| TagInfo::new(name.to_string()))
+            .collect::<Vec<_>>();
+        let selected = highest(
+            &parent,
+            &listed,
+            &StaticTagSource::new(),
+            &BumpPolicy::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(selected.name(), "8.3-dev");
+    }
+
+    #[tokio::test]
+    async fn exclude_by_constraint() {
+        let listed = [
+            "2.2.8-alpine",
+            "2.3.0-alpine",
+            "2.4.0-alpine",
+            "2.4.1-alpine",
+        ]
+        .iter()
+        .map(|name| TagInfo::new(name.to_string()))
+        .collect::<Vec<_>>();
+        let policy = BumpPolicy {
+            constraints: HashMap::from([("alpine".to_owned(), "<2.4".parse().unwrap())]),
+            ..BumpPolicy::default()
+        };
+        let selected = highest(&alpine_parent(), &listed, &StaticTagSource::new(), &policy)
+            .await
+            .unwrap();
+        assert_eq!(selected.name(), "2.3.0-alpine");
         assert_eq!(
-            highest,
-            Ok(Tag::new("3.5.2-alpine".to_owned(), (3, 5, 2, 0)))
+            selected.held_back(),
+            &vec![HeldBack::new(
+                "2.4.1-alpine".to_owned(),
+                "excluded by constraint <2.4".to_owned()
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn shared_arg_agrees() {
+        let dockerfile = Rc::new(Dockerfile::new(
+            PathBuf::from("file.ext"),
+            "ARG BASE=3.11\n\
+                FROM python:${BASE}-slim AS build\n\
+                FROM python:${BASE}-alpine\n"
+                .to_owned(),
+        ));
+        let listed = [
+            "3.11-slim",
+            "3.12-slim",
+            "3.13-slim",
+            "3.11-alpine",
+            "3.12-alpine",
+        ]
+        .iter()
+        .map(|name| TagInfo::new(name.to_string()))
+        .collect::<Vec<_>>();
+        let source = StaticTagSource::new().with_tags("python", listed);
+        let parents = extract_parents(&[dockerfile]).unwrap();
+        let latest_tags =
+            find_latest_tag(parents, &HashMap::new(), &BumpPolicy::default(), &source)
+                .await
+                .unwrap();
+        let chosen = latest_tags
+            .values()
+            .map(|tag| tag.name().as_str())
+            .sorted()
+            .collect::<Vec<_>>();
+        assert_eq!(chosen, vec!["3.12-alpine", "3.12-slim"]);
+        let held_back = latest_tags
+            .values()
+            .flat_map(|tag| tag.held_back())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            held_back,
+            vec![&HeldBack::new(
+                "3.13-slim".to_owned(),
+                "limited by the other FROM lines that use ARG BASE".to_owned()
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn ignored_line_pins_shared_arg() {
+        let dockerfile = Rc::new(Dockerfile::new(
+            PathBuf::from("file.ext"),
+            "ARG V=3.11\n\
+                # dvb:ignore\n\
+                FROM python:${V}-slim\n\
+                FROM python:${V}\n\
+                # dvb:ignore\n\
+                FROM python:${V}\n"
+                .to_owned(),
+        ));
+        let listed = ["3.11", "3.13", "3.11-slim", "3.13-slim"]
+            .iter()
+            .map(|name| TagInfo::new(name.to_string()))
+            .collect::<Vec<_>>();
+        let source = StaticTagSource::new().with_tags("python", listed);
+        let (skipped, parents): (HashSet<_>, HashSet<_>) = extract_parents(&[dockerfile])
+            .unwrap()
+            .into_iter()
+            .partition(|parent| *parent.pragmas().ignore());
+        let latest_tags = find_latest_tag(
+            parents,
+            &pinned_args(&skipped),
+            &BumpPolicy::default(),
+            &source,
+        )
+        .await
+        .unwrap();
+        assert_eq!(latest_tags.len(), 1);
+        let tag = latest_tags.values().next().unwrap();
+        assert_eq!(tag.name(), "3.11");
+        assert_eq!(
+            tag.held_back(),
+            &vec![HeldBack::new(
+                "3.13".to_owned(),
+                "limited by the other FROM lines that use ARG V".to_owned()
+            )]
         );
     }
+
+    #[tokio::test]
+    async fn keep_digest_if_unknown() {
+        let dockerfile = Rc::new(Dockerfile::new(
+            PathBuf::from("file.ext"),
+            "FROM node:20.1@sha256:aaa\n".to_owned(),
+        ));
+        let listed = ["20.1", "20.12"]
+            .iter()
+            .map(|name| TagInfo::new(name.to_string()))
+            .collect::<Vec<_>>();
+        let source = StaticTagSource::new().with_tags("node", listed);
+        let parents = extract_parents(&[dockerfile]).unwrap();
+        let latest_tags =
+            find_latest_tag(parents, &HashMap::new(), &BumpPolicy::default(), &source)
+                .await
+                .unwrap();
+        let tag = latest_tags.values().next().unwrap();
+        assert_eq!(tag.name(), "20.1");
+        assert_eq!(tag.digest(), &Some("sha256:aaa".to_owned()));
+        assert_eq!(tag.held_back().len(), 1);
+        assert_eq!(tag.held_back()[0].tag(), "20.12");
+        assert!(tag.held_back()[0]
+            .reason()
+            .starts_with("without digest: no digest known for"));
+    }
 }
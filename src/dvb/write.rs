@@ -1,3 +1,4 @@
+use ::std::ops::Range;
 use ::std::path::PathBuf;
 use ::std::task::Poll;
 
@@ -9,7 +10,6 @@ use ::indexmap::IndexMap;
 use ::log::debug;
 use ::tokio::fs::write;
 
-use crate::dvb::convert::image_tag_to_re;
 use crate::dvb::data::Tag;
 use crate::Parent;
 
@@ -51,26 +51,74 @@ async fn write_dockerfiles(path_contents: &IndexMap<PathBuf, String>) -> Result<
 fn updated_dockerfiles_content(
     latest_tags: &IndexMap<Parent, Tag>,
 ) -> Result<IndexMap<PathBuf, String>, String> {
-    let mut files: IndexMap<PathBuf, String> = IndexMap::new();
+    let mut files: IndexMap<PathBuf, (&str, Vec<Edit>)> = IndexMap::new();
     for (parent, new_tag) in latest_tags.iter() {
-        let content: &mut String = files
+        let (_, edits) = files
             .entry(parent.dockerfile().path().to_owned())
-            .or_insert_with(|| parent.dockerfile().content().to_owned());
-        let image_pattern =
-            image_tag_to_re(parent.image_name(), parent.tag().name(), parent.suffix())?;
-        let new_image = format!(
-            "FROM {}:{}{}",
-            parent.image_name(),
-            new_tag,
-            parent.suffix()
-        );
-        debug_assert!(
-            image_pattern.is_match(content),
-            "did not find image tag in dockerfile"
-        );
-        *content = image_pattern.replace_all(content, new_image).into_owned();
+            .or_insert_with(|| (parent.dockerfile().content(), vec![]));
+        let tag_span = parent
+            .tag_span()
+            .clone()
+            .ok_or_else(|| format!("position of tag in Dockerfile is unknown for {}", parent))?;
+        let new_text = match parent.tag_arg() {
+            // Bump the default of a global `ARG` (before the first FROM) instead of the FROM line itself.
+            Some(tag_arg) => tag_arg.value_for(new_tag.name()).ok_or_else(|| {
+                format!(
+                    "new tag {} does not fit the template of ARG {}",
+                    new_tag,
+                    tag_arg.name()
+                )
+            })?,
+            None => new_tag.name().to_owned(),
+        };
+        edits.push((tag_span, new_text));
+        if let (Some(digest_span), Some(new_digest)) = (parent.digest_span(), new_tag.digest()) {
+            edits.push((digest_span.clone(), new_digest.to_owned()));
+        }
+    }
+    files
+        .into_iter()
+        .map(|(path, (content, edits))| {
+            let new_content = apply_edits(content, edits).map_err(|err| {
+                format!(
+                    "could not update Dockerfile '{}': {}",
+                    path.to_string_lossy(),
+                    err
+                )
+            })?;
+            Ok((path, new_content))
+        })
+        .collect()
+}
+
+/// Byte range in a Dockerfile and the text to replace it with.
+type Edit = (Range<usize>, String);
+
+/// Replace the byte ranges, keeping everything else byte-identical. Identical edits are applied once,
+/// since FROM lines that use the same `ARG` all update its default. Different edits of the same text fail,
+/// rather than silently keeping one of them.
+fn apply_edits(content: &str, mut edits: Vec<Edit>) -> Result<String, String> {
+    edits.sort_by_key(|(span, _)| (span.start, span.end));
+    edits.dedup();
+    let mut new_content = String::with_capacity(content.len());
+    let mut position = 0;
+    let mut previous_text = "";
+    for (span, text) in &edits {
+        if span.start < position {
+            return Err(format!(
+                "'{}' would be updated to both '{}' and '{}'",
+                &content[span.clone()],
+                previous_text,
+                text
+            ));
+        }
+        new_content.push_str(&content[position..span.start]);
+        new_content.push_str(text);
+        position = span.end;
+        previous_text = text;
     }
-    Ok(files)
+    new_content.push_str(&content[position..]);
+    Ok(new_content)
 }
 
 #[cfg(test)]
@@ -78,106 +126,93 @@ mod tests {
     use ::std::rc::Rc;
 
     use ::indexmap::indexmap;
+    use ::itertools::Itertools;
 
-    use crate::dvb::convert::{parse_tag, tag_to_re};
-    use crate::dvb::data::Dockerfile;
+    use crate::dvb::data::{Dockerfile, PreRelease, PreReleaseKind};
+    use crate::dvb::read::extract_parents;
 
     use super::*;
 
-    #[test]
-    fn re_replace() {
-        let res = image_tag_to_re("namespace/image", "1.2.8-alpha", " AS build")
+    /// Parents in the order they appear in the Dockerfile, or by tag if they share an `ARG`.
+    fn parents(path: &str, content: &str) -> Vec<Parent> {
+        let dockerfile = Rc::new(Dockerfile::new(PathBuf::from(path), content.to_owned()));
+        extract_parents(&[dockerfile])
             .unwrap()
-            .replace_all(
-                "FROM  namespace/image:1.2.8-alpha  AS build\n",
-                "FROM namespace/image:1.3.2-alpha AS build".to_owned(),
-            );
-        assert_eq!("FROM namespace/image:1.3.2-alpha AS build\n", res);
+            .into_iter()
+            .sorted_by_key(|parent| {
+                let start = parent.tag_span().as_ref().unwrap().start;
+                (start, parent.tag().name().to_owned())
+            })
+            .collect()
+    }
+
+    fn bump(content: &str, new_tags: Vec<Tag>) -> String {
+        let latest_tags = parents("/fake/Dockerfile", content)
+            .into_iter()
+            .zip(new_tags)
+            .collect::<IndexMap<_, _>>();
+        let mut files = updated_dockerfiles_content(&latest_tags).unwrap();
+        assert_eq!(files.len(), 1);
+        files.pop().unwrap().1
     }
 
     #[test]
     fn single() {
-        let image = "namespace/image".to_owned();
-        let path = PathBuf::from("/fake/Dockerfile");
-        let tag_str = "1.2.4-alpha";
-        let dockerfile = Rc::new(Dockerfile::new(
-            path.clone(),
-            format!("FROM {}:{} AS build\n", &image, &tag_str),
-        ));
-        let tag_pattern = tag_to_re(tag_str).unwrap();
-        let tag_old = parse_tag(&tag_pattern, tag_str).unwrap();
         let tag_new = Tag::new("1.3.2-alpha".to_owned(), (1, 3, 2, 0));
-
-        let parent = Parent::new(
-            dockerfile,
-            image,
-            tag_pattern,
-            tag_old,
-            " AS build".to_owned(),
+        assert_eq!(
+            bump("FROM namespace/image:1.2.4-alpha AS build\n", vec![tag_new]),
+            "FROM namespace/image:1.3.2-alpha AS build\n"
         );
+    }
 
-        let tags = updated_dockerfiles_content(&indexmap![
-            parent => tag_new.clone(),
-        ])
-        .unwrap();
+    #[test]
+    fn keep_formatting() {
+        let tag_new = Tag::new("1.3.2-alpha".to_owned(), (1, 3, 2, 0));
+        assert_eq!(
+            bump(
+                "FROM  namespace/image:1.2.8-alpha  AS build\n",
+                vec![tag_new.clone()]
+            ),
+            "FROM  namespace/image:1.3.2-alpha  AS build\n"
+        );
+        assert_eq!(
+            bump(
+                "# syntax=docker/dockerfile:1\r\n  from namespace/image:1.2.8-alpha as build\r\nRUN true",
+                vec![tag_new.clone()]
+            ),
+            "# syntax=docker/dockerfile:1\r\n  from namespace/image:1.3.2-alpha as build\r\nRUN true"
+        );
         assert_eq!(
-            tags,
-            indexmap![
-                path => format!("FROM namespace/image:{} AS build\n", &tag_new),
-            ]
+            bump(
+                "FROM --platform=linux/amd64 \\\r\n    namespace/image:1.2.8-alpha \\\r\n    AS build\r\n",
+                vec![tag_new]
+            ),
+            "FROM --platform=linux/amd64 \\\r\n    namespace/image:1.3.2-alpha \\\r\n    AS build\r\n"
         );
     }
 
     #[test]
     fn multi_file_multi_tag() {
-        let image1 = "namespace/image".to_owned();
-        let tag1_str = "1.2.4-alpha";
-        let tag1_pattern = tag_to_re(tag1_str).unwrap();
-        let tag_old1 = parse_tag(&tag1_pattern, tag1_str).unwrap();
         let tag_new1 = Tag::new("1.3.2-alpha".to_owned(), (1, 3, 2, 0));
-
-        let image2 = "namespace/image2".to_owned();
-        let tag2_str = "0.3.7-rc2";
-        let tag2_pattern = tag_to_re(tag2_str).unwrap();
-        let tag_old2 = parse_tag(&tag2_pattern, tag2_str).unwrap();
-        let tag_new2 = Tag::new("0.4.4-rc1".to_owned(), (0, 4, 4, 1));
-
+        let tag_new2 = Tag::new("0.4.4-rc1".to_owned(), (0, 4, 4, 0))
+            .with_pre_release(PreRelease::new(PreReleaseKind::Rc, 1));
         let path1 = PathBuf::from("/fake/one/Dockerfile");
-        let dockerfile_a = Rc::new(Dockerfile::new(
-            path1.clone(),
-            format!(
-                "FROM {}:{} AS build\n\
-                    FROM {}:{}\n\
-                    RUN echo done",
-                &image1, &tag1_str, &image2, &tag2_str
-            ),
-        ));
         let path2 = PathBuf::from("/fake/two/Dockerfile");
-        let dockerfile_b = Rc::new(Dockerfile::new(
-            path2.clone(),
-            format!("FROM {}:{} AS pre\n", &image1, &tag1_str),
-        ));
-
-        let parent_a1 = Parent::new(
-            dockerfile_a.clone(),
-            image1,
-            tag1_pattern.clone(),
-            tag_old1.clone(),
-            " AS build".to_owned(),
+        let mut parents_a = parents(
+            "/fake/one/Dockerfile",
+            "FROM namespace/image:1.2.4-alpha AS build\n\
+                FROM namespace/image2:0.3.7-rc2\n\
+                RUN echo done",
         );
-        let parent_a2 = Parent::new(dockerfile_a, image2, tag2_pattern, tag_old2, "".to_owned());
-        let parent_b1 = Parent::new(
-            dockerfile_b,
-            "namespace/image".to_owned(),
-            tag1_pattern,
-            tag_old1,
-            " AS pre".to_owned(),
+        let mut parents_b = parents(
+            "/fake/two/Dockerfile",
+            "FROM namespace/image:1.2.4-alpha AS pre\n",
         );
 
         let tags = updated_dockerfiles_content(&indexmap![
-            parent_a1 => tag_new1.clone(),
-            parent_a2 => tag_new2,
-            parent_b1 => tag_new1,
+            parents_a.remove(0) => tag_new1.clone(),
+            parents_a.remove(0) => tag_new2,
+            parents_b.remove(0) => tag_new1,
         ])
         .unwrap();
         assert_eq!(tags.len(), 2);
@@ -187,39 +222,98 @@ mod tests {
 
     #[test]
     fn do_not_match_in_run_cmd() {
-        let image = "namespace/image".to_owned();
-        let path = PathBuf::from("/fake/Dockerfile");
-        let tag_str = "1.2.4-alpha";
-        let dockerfile = Rc::new(Dockerfile::new(
-            path.clone(),
-            format!(
-                "FROM {}:{} AS build\n\
-            RUN echo 'Using {}:{} AS build '\n",
-                &image, &tag_str, &image, &tag_str
-            ),
-        ));
-        let tag_pattern = tag_to_re(tag_str).unwrap();
-        let tag_old = parse_tag(&tag_pattern, tag_str).unwrap();
         let tag_new = Tag::new("1.3.2-alpha".to_owned(), (1, 3, 2, 0));
+        assert_eq!(
+            bump(
+                "FROM namespace/image:1.2.4-alpha AS build\n\
+                    RUN echo 'Using namespace/image:1.2.4-alpha AS build '\n\
+                    RUN <<EOF\n\
+                    FROM namespace/image:1.2.4-alpha\n\
+                    EOF\n",
+                vec![tag_new]
+            ),
+            "FROM namespace/image:1.3.2-alpha AS build\n\
+                RUN echo 'Using namespace/image:1.2.4-alpha AS build '\n\
+                RUN <<EOF\n\
+                FROM namespace/image:1.2.4-alpha\n\
+                EOF\n"
+        );
+    }
 
-        let parent = Parent::new(
-            dockerfile,
-            image,
-            tag_pattern,
-            tag_old,
-            " AS build".to_owned(),
+    #[test]
+    fn keep_platform_flags() {
+        let tag_new = Tag::new("1.82".to_owned(), (1, 82, 0, 0));
+        assert_eq!(
+            bump(
+                "FROM --platform=$BUILDPLATFORM rust:1.80 AS build\n\
+                    FROM rust:1.80\n",
+                vec![tag_new]
+            ),
+            "FROM --platform=$BUILDPLATFORM rust:1.82 AS build\nFROM rust:1.80\n"
         );
+    }
 
-        let tags = updated_dockerfiles_content(&indexmap![
-            parent => tag_new.clone(),
-        ])
-        .unwrap();
+    #[test]
+    fn bump_global_arg() {
+        let tag_new = Tag::new("3.13-slim".to_owned(), (3, 13, 0, 0));
         assert_eq!(
-            tags,
-            indexmap![
-                path => format!("FROM namespace/image:{} AS build\nRUN echo \
-                'Using namespace/image:{} AS build '\n", &tag_new, &tag_str),
-            ]
+            bump(
+                "ARG PYTHON_VERSION=\"3.11\"\n\
+                    FROM python:${PYTHON_VERSION}-slim AS build\n\
+                    ARG PYTHON_VERSION=3.11\n",
+                vec![tag_new]
+            ),
+            "ARG PYTHON_VERSION=\"3.13\"\n\
+                FROM python:${PYTHON_VERSION}-slim AS build\n\
+                ARG PYTHON_VERSION=3.11\n"
+        );
+    }
+
+    #[test]
+    fn shared_global_arg() {
+        let content = "ARG BASE=3.11 OTHER=1\n\
+            FROM python:${BASE}-slim AS build\n\
+            FROM python:${BASE}-alpine\n";
+        let tag_slim = Tag::new("3.13-slim".to_owned(), (3, 13, 0, 0));
+        let tag_alpine = Tag::new("3.13-alpine".to_owned(), (3, 13, 0, 0));
+        assert_eq!(
+            bump(content, vec![tag_alpine, tag_slim]),
+            "ARG BASE=3.13 OTHER=1\n\
+                FROM python:${BASE}-slim AS build\n\
+                FROM python:${BASE}-alpine\n"
+        );
+    }
+
+    #[test]
+    fn conflicting_global_arg() {
+        let content = "ARG BASE=3.11\n\
+            FROM python:${BASE}-slim AS build\n\
+            FROM python:${BASE}-alpine\n";
+        let tag_slim = Tag::new("3.13-slim".to_owned(), (3, 13, 0, 0));
+        let tag_alpine = Tag::new("3.12-alpine".to_owned(), (3, 12, 0, 0));
+        let latest_tags = parents("/fake/Dockerfile", content)
+            .into_iter()
+            .zip(vec![tag_alpine, tag_slim])
+            .collect::<IndexMap<_, _>>();
+        assert_eq!(
+            updated_dockerfiles_content(&latest_tags),
+            Err("could not update Dockerfile '/fake/Dockerfile': \
+                '3.11' would be updated to both '3.12' and '3.13'"
+                .to_owned())
+        );
+    }
+
+    #[test]
+    fn refresh_digest() {
+        let tag_old = Tag::new("20.11.1-alpine".to_owned(), (20, 11, 1, 0));
+        let tag_new = tag_old.clone().with_digest("sha256:2222".to_owned());
+        assert_eq!(
+            bump(
+                "FROM node:20.11.1-alpine@sha256:1111 AS build\n\
+                    FROM node:20.11.1-alpine\n",
+                vec![tag_new, tag_old]
+            ),
+            "FROM node:20.11.1-alpine@sha256:2222 AS build\nFROM node:20.11.1-alpine\n"
         );
     }
 }
@@ -4,70 +4,135 @@ use ::std::path::PathBuf;
 use ::derive_new::new;
 use ::log::debug;
 
+pub use crate::dvb::config::{ProjectConfig, DEFAULT_CONFIG_FILE};
+pub use crate::dvb::constraint::VersionConstraint;
 use crate::dvb::data::Parent;
+pub use crate::dvb::data::{HeldBack, Platform, TagInfo};
+pub use crate::dvb::discover::{find_dockerfiles, DiscoverOptions, IGNORE_FILE_NAME};
+pub use crate::dvb::local::{local_tag_source, OciLayoutTagSource};
+pub use crate::dvb::parse::BaseKind;
+pub use crate::dvb::policy::{BumpLevel, BumpPolicy};
 use crate::dvb::read::{extract_parents, read_all_dockerfiles};
-use crate::dvb::uptag::find_latest_tag;
+pub use crate::dvb::registry::{ImageRef, Registry, RegistryOptions};
+pub use crate::dvb::source::{
+    DockerHubTagSource, OciTagSource, RegistryTagSource, StaticTagSource, TagSource,
+};
+use crate::dvb::uptag::{find_latest_tag, pinned_args};
 use crate::dvb::write::update_all_dockerfiles;
 
 mod dvb;
 
-/// Unless dry-run, bump all the Dockerfiles for which there is a new matching version.
-/// returns: (dockerfile path, from-image name, old tag, new tag) if successful, error message otherwise
+/// Unless dry-run, bump all the Dockerfiles for which there is a new matching version in the tag source.
+/// returns: (dockerfile path, from-image name, old tag, new tag, old and new digest, held back tags) if successful, error message otherwise
 pub async fn bump_dockerfiles(
     dockerfiles: &[PathBuf],
     allow_parents: &[String],
-    bump_major: bool,
+    policy: &BumpPolicy,
+    tag_source: &dyn TagSource,
     dry_run: bool,
 ) -> Result<Vec<TagUp>, String> {
     let dockerfiles = read_all_dockerfiles(dockerfiles).await?;
     let all_parents = extract_parents(&dockerfiles)?;
-    let parents = filter_parents(all_parents, allow_parents)?;
-    let latest_tags = find_latest_tag(parents, bump_major).await?;
+    let (parents, skipped) = filter_parents(all_parents, allow_parents)?;
+    let latest_tags = find_latest_tag(parents, &pinned_args(&skipped), policy, tag_source).await?;
     update_all_dockerfiles(&latest_tags, dry_run).await?;
     Ok(latest_tags
         .into_iter()
         .map(|(parent, new_tag)| (parent.explode(), new_tag))
-        .map(|((dockerfile, name, old_tag), new_tag)| {
+        .map(|((dockerfile, name, old_tag, old_digest), new_tag)| {
             TagUp::new(
                 dockerfile,
                 name,
                 old_tag.name().to_owned(),
                 new_tag.name().to_owned(),
+                old_digest,
+                new_tag.digest().to_owned(),
+                new_tag.held_back().to_owned(),
             )
         })
         .collect())
 }
 
+/// The build stages of the Dockerfiles, with the image, earlier stage or `scratch` that each is based on.
+pub async fn dockerfile_stages(dockerfiles: &[PathBuf]) -> Result<Vec<DockerfileStage>, String> {
+    let dockerfiles = read_all_dockerfiles(dockerfiles).await?;
+    Ok(dockerfiles
+        .iter()
+        .flat_map(|dockerfile| {
+            dockerfile
+                .stages()
+                .iter()
+                .enumerate()
+                .map(|(index, stage)| {
+                    DockerfileStage::new(
+                        dockerfile.path().to_owned(),
+                        index,
+                        stage.alias().to_owned(),
+                        stage.base().to_owned(),
+                        *stage.kind(),
+                    )
+                })
+        })
+        .collect())
+}
+
+#[derive(Debug, Clone, new)]
+pub struct DockerfileStage {
+    pub dockerfile: PathBuf,
+    /// Position of the stage in the Dockerfile, starting at 0.
+    pub index: usize,
+    /// Name given with `AS`, if any.
+    pub name: Option<String>,
+    pub base: String,
+    pub kind: BaseKind,
+}
+
 #[derive(Debug, Clone, new)]
 pub struct TagUp {
     pub dockerfile: PathBuf,
     pub image: String,
     pub old_tag: String,
     pub new_tag: String,
+    pub old_digest: Option<String>,
+    pub new_digest: Option<String>,
+    /// Newer tags that were not chosen because of the bump policy.
+    pub held_back: Vec<HeldBack>,
 }
 
+impl TagUp {
+    pub fn is_update(&self) -> bool {
+        self.old_tag != self.new_tag || self.old_digest != self.new_digest
+    }
+}
+
+/// Split the parents into those to bump and those that are skipped, because of `dvb:ignore` or `-p`.
 fn filter_parents(
     all_parents: HashSet<Parent>,
     allow_parent_names: &[String],
-) -> Result<HashSet<Parent>, String> {
+) -> Result<(HashSet<Parent>, HashSet<Parent>), String> {
+    let (all_parents, mut skipped): (HashSet<_>, HashSet<_>) = all_parents
+        .into_iter()
+        .partition(|parent| !parent.pragmas().ignore());
     if allow_parent_names.is_empty() {
         if all_parents.is_empty() {
             return Err("No FROM tags with versions were found in the Dockerfile(s)".to_owned());
         }
-        return Ok(all_parents);
+        return Ok((all_parents, skipped));
     }
     let allow_parent_names: HashSet<String> =
         HashSet::from_iter(allow_parent_names.iter().cloned());
-    let parents = all_parents
+    let (parents, not_allowed): (HashSet<_>, HashSet<_>) = all_parents
         .into_iter()
-        .filter(|parent| allow_parent_names.contains(parent.image_name()))
-        .inspect(|parent| debug!("including parent (-p): {}", parent))
-        .collect::<HashSet<_>>();
+        .partition(|parent| allow_parent_names.contains(parent.image_name()));
+    for parent in &parents {
+        debug!("including parent (-p): {}", parent);
+    }
     if parents.is_empty() {
         return Err(
             "None of the FROM tags given with --parent/-p were found in the Dockerfile(s)"
                 .to_owned(),
         );
     }
-    Ok(parents)
+    skipped.extend(not_allowed);
+    Ok((parents, skipped))
 }
@@ -1,10 +1,24 @@
 use ::std::path::PathBuf;
 use ::std::process::exit;
+use ::std::time::Duration;
 use ::std::time::SystemTime;
 
 use ::clap::Parser;
 use ::derive_getters::Getters;
 use ::dockerfile_version_bumper::bump_dockerfiles;
+use ::dockerfile_version_bumper::BumpLevel;
+use ::dockerfile_version_bumper::BumpPolicy;
+use ::dockerfile_version_bumper::DEFAULT_CONFIG_FILE;
+use ::dockerfile_version_bumper::DiscoverOptions;
+use ::dockerfile_version_bumper::DockerfileStage;
+use ::dockerfile_version_bumper::dockerfile_stages;
+use ::dockerfile_version_bumper::find_dockerfiles;
+use ::dockerfile_version_bumper::local_tag_source;
+use ::dockerfile_version_bumper::Platform;
+use ::dockerfile_version_bumper::ProjectConfig;
+use ::dockerfile_version_bumper::RegistryOptions;
+use ::dockerfile_version_bumper::RegistryTagSource;
+use ::dockerfile_version_bumper::TagSource;
 use ::dockerfile_version_bumper::TagUp;
 use ::env_logger;
 use ::tokio;
@@ -20,28 +34,126 @@ static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
 )]
 /// CLI arguments. Readme will be updated by release build.
 pub struct Args {
+    /// Dockerfiles to bump. Directories are searched recursively for Dockerfile, *.Dockerfile, Dockerfile.* and Containerfile. Glob patterns like `services/**/Dockerfile` are also accepted.
     #[clap(
         long = "dockerfile",
         short = 'f',
         default_value = "Dockerfile",
     )]
     dockerfiles: Vec<PathBuf>,
+    /// Skip files and directories matching this glob pattern when searching, like `legacy` or `**/testdata/**`. Can be given multiple times.
+    #[clap(
+        long = "exclude",
+    )]
+    excludes: Vec<String>,
+    /// File with exclude patterns in .gitignore syntax, like a .dockerignore. Files ignored by .gitignore and .dvbignore are always skipped.
+    #[clap(
+        long = "ignore-file",
+    )]
+    ignore_files: Vec<PathBuf>,
     /// Parent images (FROM lines) base names that should be bumped. If empty, bumps every image in the Dockerfile that is found in the registry.
     #[clap(
         long = "parent",
         short = 'p',
     )]
     parents: Vec<String>,
-    /// Allow bumping to new major versions (which might be incompatible), which is interpreted as the leading number in the version.
+    /// Allow bumping to new major versions (which might be incompatible), which is interpreted as the leading number in the version. Same as `--level major`.
     #[clap(
         long = "major",
+        conflicts_with = "level",
     )]
     bump_major: bool,
+    /// Which version numbers may change: patch (third and later numbers), minor (second and later) or major (any).
+    #[clap(
+        long = "level",
+        default_value = "minor",
+    )]
+    level: BumpLevel,
+    /// Bump level for one image, like `python=patch`. Can be given multiple times.
+    #[clap(
+        long = "image-level",
+    )]
+    image_levels: Vec<String>,
+    /// Version constraint for one image, like `postgres=<17` or `python=>=3.11, <3.13`. Can be given multiple times.
+    #[clap(
+        long = "constraint",
+    )]
+    constraints: Vec<String>,
+    /// Project config file with per-image levels and constraints. Defaults to `.dvb.json` if it exists.
+    #[clap(
+        long = "config",
+    )]
+    config: Option<PathBuf>,
+    /// Allow bumping to pre-releases like 3.14.0rc1. Without this, pre-releases are only chosen if the current tag is a pre-release of the same version.
+    #[clap(
+        long = "pre-release",
+    )]
+    pre_release: bool,
+    /// Only bump to tags that are available for this platform, like linux/arm64. Can be given multiple times. A literal `--platform` in a FROM line is also required for that image.
+    #[clap(
+        long = "platform",
+    )]
+    platforms: Vec<String>,
+    /// Only bump to tags that were pushed at least this many days ago, if the registry provides the push time (Docker Hub does).
+    #[clap(
+        long = "min-age",
+        default_value = "0",
+    )]
+    min_age_days: u64,
+    /// How many images to look up in registries at the same time.
+    #[clap(
+        long = "concurrency",
+        default_value = "8",
+    )]
+    concurrency: usize,
+    /// Stop after this many pages when listing the tags of an image. Follows all pages by default.
+    #[clap(
+        long = "max-tag-pages",
+    )]
+    max_tag_pages: Option<usize>,
+    /// How often to retry registry requests that fail because of network errors, rate limiting or server errors.
+    #[clap(
+        long = "retries",
+        default_value = "3",
+    )]
+    retries: u32,
+    /// Directory in which tag listings are cached. Defaults to the user cache directory.
+    #[clap(
+        long = "cache-dir",
+    )]
+    cache_dir: Option<PathBuf>,
+    /// Use cached tag listings that are younger than this many seconds, instead of requesting them again.
+    #[clap(
+        long = "cache-ttl",
+        default_value = "0",
+    )]
+    cache_ttl: u64,
+    /// Neither read nor write the tag listing cache.
+    #[clap(
+        long = "no-cache",
+    )]
+    no_cache: bool,
+    /// Only use cached tag listings, and fail if an image is not in the cache.
+    #[clap(
+        long = "offline",
+        conflicts_with = "no_cache",
+    )]
+    offline: bool,
+    /// Take tags from an OCI image layout directory or a json snapshot file, instead of from registries.
+    #[clap(
+        long = "tags-from",
+    )]
+    tags_from: Option<PathBuf>,
     /// Print the output instead of updating in-place (dry run).
     #[clap(
         long = "dry-run",
     )]
     dry_run: bool,
+    /// Instead of bumping, list the stages of each Dockerfile with the image, earlier stage or scratch that they are based on.
+    #[clap(
+        long = "stages",
+    )]
+    stages: bool,
     /// Print version bumps in json format. Still bumps Dockerfiles unless --dry-run is also given.
     #[clap(
         long = "json",
@@ -54,10 +166,71 @@ async fn main() {
     let start = SystemTime::now();
     env_logger::init();
     let args = Args::parse();
+    let discover_options = DiscoverOptions {
+        excludes: args.excludes().clone(),
+        ignore_files: args.ignore_files().clone(),
+    };
+    let dockerfiles = match find_dockerfiles(args.dockerfiles(), &discover_options) {
+        Ok(dockerfiles) => dockerfiles,
+        Err(err) => {
+            eprintln!("Fatal! {}", err);
+            exit(1);
+        }
+    };
+    if *args.stages() {
+        match dockerfile_stages(&dockerfiles).await {
+            Ok(stages) => {
+                if *args.json() {
+                    print_stages_json(&stages);
+                } else {
+                    print_stages_text(&stages);
+                }
+            }
+            Err(err) => {
+                eprintln!("Fatal! {}", err);
+                exit(1);
+            }
+        }
+        return;
+    }
+    let cache_dir = if *args.no_cache() {
+        None
+    } else {
+        args.cache_dir()
+            .clone()
+            .or_else(RegistryOptions::default_cache_dir)
+    };
+    let registry_options = RegistryOptions {
+        cache_dir,
+        cache_ttl: Duration::from_secs(*args.cache_ttl()),
+        offline: *args.offline(),
+        concurrency: *args.concurrency(),
+        max_tag_pages: *args.max_tag_pages(),
+        max_retries: *args.retries(),
+        ..RegistryOptions::default()
+    };
+    let policy = match bump_policy(&args).await {
+        Ok(policy) => policy,
+        Err(err) => {
+            eprintln!("Fatal! {}", err);
+            exit(1);
+        }
+    };
+    let tag_source: Box<dyn TagSource> = match args.tags_from() {
+        Some(path) => match local_tag_source(path).await {
+            Ok(tag_source) => tag_source,
+            Err(err) => {
+                eprintln!("Fatal! {}", err);
+                exit(1);
+            }
+        },
+        None => Box::new(RegistryTagSource::new(&registry_options)),
+    };
     match bump_dockerfiles(
-        args.dockerfiles(),
+        &dockerfiles,
         args.parents(),
-        *args.bump_major(),
+        &policy,
+        tag_source.as_ref(),
         *args.dry_run(),
     )
     .await
@@ -80,6 +253,91 @@ async fn main() {
     }
 }
 
+/// Policy from the project config file, overridden by the command line.
+async fn bump_policy(args: &Args) -> Result<BumpPolicy, String> {
+    let platforms = args
+        .platforms()
+        .iter()
+        .map(|platform| Platform::parse(platform))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut policy = BumpPolicy {
+        level: if *args.bump_major() {
+            BumpLevel::Major
+        } else {
+            *args.level()
+        },
+        pre_release: *args.pre_release(),
+        platforms,
+        min_age: Duration::from_secs(*args.min_age_days() * 24 * 60 * 60),
+        ..BumpPolicy::default()
+    };
+    let default_config = PathBuf::from(DEFAULT_CONFIG_FILE);
+    let config_path = match args.config() {
+        Some(path) => Some(path.clone()),
+        None if default_config.is_file() => Some(default_config),
+        None => None,
+    };
+    if let Some(config_path) = config_path {
+        ProjectConfig::load(&config_path)
+            .await?
+            .apply_to(&mut policy)?;
+    }
+    for image_level in args.image_levels() {
+        let (image, level) = parse_image_setting(image_level)?;
+        policy.image_levels.insert(image, level.parse()?);
+    }
+    for constraint in args.constraints() {
+        let (image, constraint) = parse_image_setting(constraint)?;
+        policy.constraints.insert(image, constraint.parse()?);
+    }
+    Ok(policy)
+}
+
+/// Split an image setting like `python=patch` or `postgres=<17` at the first `=`.
+fn parse_image_setting(setting: &str) -> Result<(String, &str), String> {
+    let (image, value) = setting.split_once('=').ok_or_else(|| {
+        format!(
+            "invalid image setting '{}', expected image=value like python=patch",
+            setting
+        )
+    })?;
+    Ok((image.trim().to_owned(), value.trim()))
+}
+
+fn print_stages_json(stages: &[DockerfileStage]) {
+    println!("[");
+    for (index, stage) in stages.iter().enumerate() {
+        if index > 0 {
+            println!(",");
+        }
+        print!("  {{\"dockerfile\": \"{}\", ", stage.dockerfile.to_string_lossy());
+        print!("\"index\": {}, ", stage.index);
+        match &stage.name {
+            Some(name) => print!("\"name\": \"{}\", ", name),
+            None => print!("\"name\": null, "),
+        }
+        print!("\"base\": \"{}\", ", stage.base);
+        print!("\"kind\": \"{}\"}}", stage.kind);
+    }
+    println!("\n]");
+}
+
+fn print_stages_text(stages: &[DockerfileStage]) {
+    for stage in stages {
+        let name = stage
+            .name
+            .clone()
+            .unwrap_or_else(|| format!("#{}", stage.index));
+        println!(
+            "{}\t{}\t{} ({})",
+            stage.dockerfile.to_string_lossy(),
+            name,
+            stage.base,
+            stage.kind
+        )
+    }
+}
+
 fn print_tags_json(parent_latest_tags: &[TagUp]) {
     let mut is_first = true;
     println!("[");
@@ -93,24 +351,57 @@ fn print_tags_json(parent_latest_tags: &[TagUp]) {
         print!("\"dockerfile\": \"{}\", ", up.dockerfile.to_string_lossy());
         print!("\"current_tag\": \"{}\", ", up.old_tag);
         print!("\"updated_tag\": \"{}\", ", up.new_tag);
-        print!("\"is_update\": {}}}", up.old_tag != up.new_tag);
+        if let Some(old_digest) = &up.old_digest {
+            print!("\"current_digest\": \"{}\", ", old_digest);
+        }
+        if let Some(new_digest) = &up.new_digest {
+            print!("\"updated_digest\": \"{}\", ", new_digest);
+        }
+        if !up.held_back.is_empty() {
+            print!("\"held_back\": [");
+            for (index, held_back) in up.held_back.iter().enumerate() {
+                if index > 0 {
+                    print!(", ");
+                }
+                print!(
+                    "{{\"tag\": \"{}\", \"reason\": \"{}\"}}",
+                    held_back.tag(),
+                    held_back.reason()
+                );
+            }
+            print!("], ");
+        }
+        print!("\"is_update\": {}}}", up.is_update());
     }
     println!("\n]");
 }
 
 fn print_tags_text(parent_latest_tags: &[TagUp]) {
     for up in parent_latest_tags {
-        if up.old_tag == up.new_tag {
+        if !up.is_update() {
             println!("{}\t{} (up-to-date)", up.image, up.old_tag)
+        } else if up.old_tag == up.new_tag {
+            println!(
+                "{}\t{} (digest {} -> {})",
+                up.image,
+                up.old_tag,
+                up.old_digest.as_deref().unwrap_or("none"),
+                up.new_digest.as_deref().unwrap_or("none")
+            )
         } else {
             println!("{}\t{} -> {}", up.image, up.old_tag, up.new_tag)
         }
+        for held_back in &up.held_back {
+            println!("\t  held back {}", held_back)
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use ::dockerfile_version_bumper::StaticTagSource;
+    use ::dockerfile_version_bumper::TagInfo;
     use ::std::fs;
     use ::tempfile::NamedTempFile;
 
@@ -123,7 +414,8 @@ mod tests {
         bump_dockerfiles(
             &[temp_file.path().to_path_buf()],
             &[],
-            false,
+            &BumpPolicy::default(),
+            &RegistryTagSource::new(&RegistryOptions::default()),
             false,
         ).await.unwrap();
         let content = fs::read_to_string(temp_file.path()).unwrap();
@@ -131,4 +423,26 @@ mod tests {
         assert!(*content >= *"FROM python:3.13");
         assert!(content.ends_with(" AS build\n"))
     }
+
+    #[tokio::test]
+    async fn full_bump_with_static_source() {
+        let temp_file = NamedTempFile::new().unwrap();
+        fs::write(&temp_file, "FROM python:3.11.2-slim AS build\nFROM node:20.1\n").unwrap();
+        let tags = |names: &[&str]| names.iter()
+            .map(|name| TagInfo::new(name.to_string()))
+            .collect::<Vec<_>>();
+        let source = StaticTagSource::new()
+            .with_tags("python", tags(&["3.11.1-slim", "3.11.9-slim", "3.11.10", "4.0.0-slim"]))
+            .with_tags("node", tags(&["20.1", "20.12", "22.0"]));
+        let bumps = bump_dockerfiles(
+            &[temp_file.path().to_path_buf()],
+            &[],
+            &BumpPolicy::default(),
+            &source,
+            false,
+        ).await.unwrap();
+        assert_eq!(bumps.len(), 2);
+        let content = fs::read_to_string(temp_file.path()).unwrap();
+        assert_eq!(content, "FROM python:3.11.9-slim AS build\nFROM node:20.12\n");
+    }
 }